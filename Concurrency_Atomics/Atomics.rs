@@ -166,63 +166,4 @@ fn get_key() -> u64 {
     }
 }
 ///////////////////////////////////////
-use std::sync::{Arc, RwLock};
-use std::thread;
-use std::time::Duration;
-use std::collections::HashMap;
-
-struct Bank {
-    accounts: RwLock<HashMap<String, f64>>,
-}
-
-impl Bank {
-    fn new() -> Self {
-        Bank {
-            accounts: RwLock::new(HashMap::new()),
-        }
-    }
-
-    fn deposit(&self, account: &str, amount: f64) {
-        let mut accounts = self.accounts.write().unwrap();
-        let balance = accounts.entry(account.to_string()).or_insert(0.0);
-        *balance += amount;
-    }
-
-    fn withdraw(&self, account: &str, amount: f64) {
-        let mut accounts = self.accounts.write().unwrap();
-        if let Some(balance) = accounts.get_mut(account) {
-            if *balance >= amount {
-                *balance -= amount;
-            } else {
-                println!("Insufficient funds for account: {}", account);
-            }
-        }
-    }
-
-    fn check_balance(&self, account: &str) -> f64 {
-        let accounts = self.accounts.read().unwrap();
-        *accounts.get(account).unwrap_or(&0.0)
-    }
-}
-
-fn main() {
-    let bank = Arc::new(Bank::new());
-    let mut handles = vec![];
-
-    for i in 0..5 {
-        let bank_clone = Arc::clone(&bank);
-        let handle = thread::spawn(move || {
-            let account = format!("Account{}", i);
-            bank_clone.deposit(&account, 100.0);
-            thread::sleep(Duration::from_millis(100)); // Simulate other work
-            bank_clone.withdraw(&account, 30.0);
-            let balance = bank_clone.check_balance(&account);
-            println!("{} - Balance: {:.2}", account, balance);
-        });
-        handles.push(handle);
-    }
-
-    for handle in handles {
-        handle.join().unwrap();
-    }
-}
+// Bank example -> bank.rs (it builds on its own: rustc --test bank.rs)
@@ -0,0 +1,918 @@
+// The Bank example from Atomics.rs, in its own file so it compiles: rustc --test bank.rs
+
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering::Relaxed};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+
+// Whole cents, f64 can't hold 0.10 exactly and the pennies drift after enough deposits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+struct Cents(i64);
+
+impl Cents {
+    fn dollars(dollars: i64) -> Self {
+        Cents(dollars * 100)
+    }
+}
+
+impl fmt::Display for Cents {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        write!(f, "{}${}.{:02}", sign, self.0.abs() / 100, self.0.abs() % 100)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum BankError {
+    InsufficientFunds { account: String, balance: Cents, wanted: Cents },
+    UnknownAccount(String),
+    NegativeAmount(Cents),
+    LockPoisoned(String),
+    Overflow { account: String, amount: Cents },
+}
+
+impl fmt::Display for BankError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BankError::InsufficientFunds { account, balance, wanted } => {
+                write!(f, "Insufficient funds for account: {} has {}, wanted {}", account, balance, wanted)
+            }
+            BankError::UnknownAccount(account) => write!(f, "No such account: {}", account),
+            BankError::NegativeAmount(amount) => write!(f, "Amounts can't be negative: {}", amount),
+            BankError::LockPoisoned(account) => write!(f, "Account {} was left in a bad state by a panic", account),
+            BankError::Overflow { account, amount } => write!(f, "Adding {} to {} is more than the bank can count", amount, account),
+        }
+    }
+}
+
+impl Error for BankError {}
+
+// A thread that panicked while holding an account poisons it... the balance is a single value, so take it back with
+// into_inner (Locks.rs) as long as it still makes sense. A negative balance can't be trusted, that one stays poisoned
+fn lock_account<'a>(account: &str, balance: &'a Mutex<Cents>) -> Result<MutexGuard<'a, Cents>, BankError> {
+    match balance.lock() {
+        Ok(guard) => Ok(guard),
+        Err(poisoned) => {
+            let guard = poisoned.into_inner();
+            if *guard < Cents(0) {
+                return Err(BankError::LockPoisoned(account.to_string()));
+            }
+            balance.clear_poison();
+            Ok(guard)
+        }
+    }
+}
+
+fn check_amount(amount: Cents) -> Result<(), BankError> {
+    if amount < Cents(0) {
+        return Err(BankError::NegativeAmount(amount));
+    }
+    Ok(())
+}
+
+// checked_add so a huge deposit is an error, not a panic with the account still locked (that would poison it)
+fn credit(account: &str, balance: Cents, amount: Cents) -> Result<Cents, BankError> {
+    balance.0
+        .checked_add(amount.0)
+        .map(Cents)
+        .ok_or(BankError::Overflow { account: account.to_string(), amount })
+}
+
+// Seconds since the UNIX epoch, swapped out in tests so statements have known dates
+trait Clock: Send + Sync {
+    fn now(&self) -> u64;
+}
+
+struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+    }
+}
+
+/*
+    - Double entry: every entry takes the amount out of one side and puts it into the other, so the whole journal nets to 0
+    - None is the bank's cash drawer, where deposits come from and withdrawals go to
+    - Entries are written while the accounts they touch are still locked, so per account the journal is in the same
+      order the balance changed
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct JournalEntry {
+    id: u64,
+    at: u64,
+    from: Option<String>,
+    to: Option<String>,
+    amount: Cents,
+}
+
+impl JournalEntry {
+    // What this entry did to one account, + in and - out
+    fn effect_on(&self, account: &str) -> i64 {
+        let mut effect = 0;
+        if self.from.as_deref() == Some(account) {
+            effect -= self.amount.0;
+        }
+        if self.to.as_deref() == Some(account) {
+            effect += self.amount.0;
+        }
+        effect
+    }
+
+    fn description(&self) -> String {
+        match (&self.from, &self.to) {
+            (None, Some(_)) => String::from("Deposit"),
+            (Some(_), None) => String::from("Withdrawal"),
+            (Some(from), Some(to)) => format!("Transfer {} -> {}", from, to),
+            (None, None) => String::from("Nothing"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct StatementLine {
+    id: u64,
+    at: u64,
+    description: String,
+    amount: i64, // cents, + in and - out
+    balance: Cents,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Statement {
+    account: String,
+    opening: Cents,
+    lines: Vec<StatementLine>,
+    closing: Cents,
+}
+
+impl fmt::Display for Statement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Statement for {}", self.account)?;
+        writeln!(f, "{:>6} {:>12} {:<32}{:>12}{:>12}", "", "", "Opening balance", "", self.opening.to_string())?;
+        for line in &self.lines {
+            let amount = if line.amount < 0 { format!("-{}", Cents(-line.amount)) } else { Cents(line.amount).to_string() };
+            writeln!(f, "{:>6} {:>12} {:<32}{:>12}{:>12}", line.id, line.at, line.description, amount, line.balance.to_string())?;
+        }
+        write!(f, "{:>6} {:>12} {:<32}{:>12}{:>12}", "", "", "Closing balance", "", self.closing.to_string())
+    }
+}
+
+/*
+    - Every balance at one moment, built from the journal rather than by locking every account
+    - Each operation is one entry pushed while its accounts are locked, so any prefix of the journal Vec is a moment
+      where every transfer is either all there or not there at all
+    - Copy-on-write in the spirit of RCU: the next snapshot is a copy of the last one with the new entries applied,
+      then it's swapped in... anyone still holding the old Arc keeps reading the old moment undisturbed
+*/
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+struct Snapshot {
+    entries: usize, // how much of the journal is in here, only ever goes up
+    taken_at: u64,
+    balances: BTreeMap<String, Cents>,
+}
+
+impl Snapshot {
+    fn balance(&self, account: &str) -> Option<Cents> {
+        self.balances.get(account).copied()
+    }
+
+    fn total(&self) -> Cents {
+        Cents(self.balances.values().map(|balance| balance.0).sum())
+    }
+}
+
+// An account whose balance doesn't match what the journal says it should be
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Discrepancy {
+    account: String,
+    balance: Cents,
+    journal: Cents,
+}
+
+// Every account gets its own lock so transfers between different accounts don't wait on each other,
+// the map's write lock is only needed to open a new account
+struct Bank {
+    accounts: RwLock<HashMap<String, Mutex<Cents>>>,
+    journal: Mutex<Vec<JournalEntry>>,
+    next_id: AtomicU64,
+    clock: Box<dyn Clock>,
+    snapshot: Mutex<Arc<Snapshot>>, // the latest one handed out, the next is built on top of it
+}
+
+impl Bank {
+    fn new() -> Self {
+        Bank::with_clock(Box::new(SystemClock))
+    }
+
+    fn with_clock(clock: Box<dyn Clock>) -> Self {
+        Bank {
+            accounts: RwLock::new(HashMap::new()),
+            journal: Mutex::new(Vec::new()),
+            next_id: AtomicU64::new(1),
+            clock,
+            snapshot: Mutex::new(Arc::new(Snapshot::default())),
+        }
+    }
+
+    // Called with the accounts involved still locked. The ids come off an atomic counter so they only ever go up,
+    // two entries can land in the Vec out of id order though, anything reading the journal sorts by id
+    fn record(&self, from: Option<&str>, to: Option<&str>, amount: Cents) {
+        let entry = JournalEntry {
+            id: self.next_id.fetch_add(1, Relaxed),
+            at: self.clock.now(),
+            from: from.map(String::from),
+            to: to.map(String::from),
+            amount,
+        };
+        self.journal.lock().unwrap_or_else(PoisonError::into_inner).push(entry);
+    }
+
+    fn entries(&self) -> Vec<JournalEntry> {
+        let mut entries = self.journal.lock().unwrap_or_else(PoisonError::into_inner).clone();
+        entries.sort_by_key(|entry| entry.id);
+        entries
+    }
+
+    // Deposits and transfers only wait while the new end of the journal is copied out, the rest happens without it.
+    // Goes by the order entries landed in the journal, not by id, that's the order they really happened in
+    fn snapshot(&self) -> Arc<Snapshot> {
+        let mut latest = self.snapshot.lock().unwrap_or_else(PoisonError::into_inner);
+        let new_entries: Vec<JournalEntry> = self.journal.lock().unwrap_or_else(PoisonError::into_inner)[latest.entries..].to_vec();
+        if new_entries.is_empty() {
+            return Arc::clone(&latest);
+        }
+
+        let mut next = Snapshot::clone(&latest);
+        for entry in &new_entries {
+            for (account, effect) in [(&entry.from, -entry.amount.0), (&entry.to, entry.amount.0)] {
+                if let Some(account) = account {
+                    let balance = next.balances.entry(account.clone()).or_default();
+                    *balance = Cents(balance.0 + effect);
+                }
+            }
+        }
+        next.entries += new_entries.len();
+        next.taken_at = self.clock.now();
+
+        *latest = Arc::new(next);
+        Arc::clone(&latest)
+    }
+
+    // Opens the account if it's new, hands back the balance after the deposit
+    fn deposit(&self, account: &str, amount: Cents) -> Result<Cents, BankError> {
+        check_amount(amount)?;
+
+        // The map is only ever changed by inserting a whole account, a poisoned map is still fine to read
+        if let Some(balance) = self.accounts.read().unwrap_or_else(PoisonError::into_inner).get(account) {
+            let mut balance = lock_account(account, balance)?;
+            *balance = credit(account, *balance, amount)?;
+            self.record(None, Some(account), amount);
+            return Ok(*balance);
+        }
+
+        // New account... someone else may have opened it between the two locks, entry() covers that
+        let mut accounts = self.accounts.write().unwrap_or_else(PoisonError::into_inner);
+        let balance = accounts.entry(account.to_string()).or_insert_with(|| Mutex::new(Cents(0)));
+        let mut balance = lock_account(account, balance)?;
+        *balance = credit(account, *balance, amount)?;
+        self.record(None, Some(account), amount);
+        Ok(*balance)
+    }
+
+    fn withdraw(&self, account: &str, amount: Cents) -> Result<Cents, BankError> {
+        check_amount(amount)?;
+
+        let accounts = self.accounts.read().unwrap_or_else(PoisonError::into_inner);
+        let balance = accounts.get(account).ok_or(BankError::UnknownAccount(account.to_string()))?;
+        let mut balance = lock_account(account, balance)?;
+        if *balance < amount {
+            return Err(BankError::InsufficientFunds { account: account.to_string(), balance: *balance, wanted: amount });
+        }
+
+        *balance = Cents(balance.0 - amount.0);
+        self.record(Some(account), None, amount);
+        Ok(*balance)
+    }
+
+    // All or nothing: both balances are locked before either changes. The locks are always taken in name order,
+    // otherwise A->B and B->A at the same time could each grab one lock and wait on the other forever
+    fn transfer(&self, from: &str, to: &str, amount: Cents) -> Result<(), BankError> {
+        check_amount(amount)?;
+
+        let accounts = self.accounts.read().unwrap_or_else(PoisonError::into_inner);
+        let source = accounts.get(from).ok_or(BankError::UnknownAccount(from.to_string()))?;
+        let target = accounts.get(to).ok_or(BankError::UnknownAccount(to.to_string()))?;
+
+        // Same account, locking it twice would deadlock... nothing moves anyway
+        if from == to {
+            let balance = lock_account(from, source)?;
+            return if *balance >= amount {
+                Ok(())
+            } else {
+                Err(BankError::InsufficientFunds { account: from.to_string(), balance: *balance, wanted: amount })
+            };
+        }
+
+        let (mut from_balance, mut to_balance) = if from < to {
+            let from_balance = lock_account(from, source)?;
+            (from_balance, lock_account(to, target)?)
+        } else {
+            let to_balance = lock_account(to, target)?;
+            (lock_account(from, source)?, to_balance)
+        };
+
+        if *from_balance < amount {
+            return Err(BankError::InsufficientFunds { account: from.to_string(), balance: *from_balance, wanted: amount });
+        }
+        *to_balance = credit(to, *to_balance, amount)?;
+        *from_balance = Cents(from_balance.0 - amount.0);
+        self.record(Some(from), Some(to), amount);
+
+        Ok(())
+    }
+
+    fn check_balance(&self, account: &str) -> Result<Cents, BankError> {
+        let accounts = self.accounts.read().unwrap_or_else(PoisonError::into_inner);
+        let balance = accounts.get(account).ok_or(BankError::UnknownAccount(account.to_string()))?;
+        let balance = lock_account(account, balance)?;
+        Ok(*balance)
+    }
+
+    // Everything that happened to an account in [from, to), seconds since the epoch
+    fn statement(&self, account: &str, from: u64, to: u64) -> Result<Statement, BankError> {
+        if !self.accounts.read().unwrap_or_else(PoisonError::into_inner).contains_key(account) {
+            return Err(BankError::UnknownAccount(account.to_string()));
+        }
+
+        let mut balance = 0;
+        let mut opening = 0;
+        let mut lines = Vec::new();
+        for entry in self.entries() {
+            if entry.from.as_deref() != Some(account) && entry.to.as_deref() != Some(account) {
+                continue;
+            }
+            if entry.at >= to {
+                break;
+            }
+
+            let effect = entry.effect_on(account);
+            balance += effect;
+            if entry.at < from {
+                opening = balance;
+            } else {
+                lines.push(StatementLine { id: entry.id, at: entry.at, description: entry.description(), amount: effect, balance: Cents(balance) });
+            }
+        }
+
+        Ok(Statement { account: account.to_string(), opening: Cents(opening), lines, closing: Cents(balance) })
+    }
+
+    // Replays the whole journal and compares it with every balance. All the accounts are locked (in name order, same as
+    // transfer) so nothing can be half way through while we look
+    fn check_consistency(&self) -> Result<(), Vec<Discrepancy>> {
+        let accounts = self.accounts.read().unwrap_or_else(PoisonError::into_inner);
+        let mut names: Vec<&String> = accounts.keys().collect();
+        names.sort();
+        let balances: Vec<(&String, MutexGuard<Cents>)> = names
+            .into_iter()
+            .map(|name| (name, accounts[name].lock().unwrap_or_else(PoisonError::into_inner)))
+            .collect();
+
+        let mut replayed: BTreeMap<&str, i64> = BTreeMap::new();
+        let entries = self.entries();
+        for entry in &entries {
+            for (account, effect) in [(&entry.from, -entry.amount.0), (&entry.to, entry.amount.0)] {
+                if let Some(account) = account {
+                    *replayed.entry(account.as_str()).or_insert(0) += effect;
+                }
+            }
+        }
+
+        let mut discrepancies: Vec<Discrepancy> = balances
+            .iter()
+            .filter(|(name, balance)| replayed.get(name.as_str()).copied().unwrap_or(0) != balance.0)
+            .map(|(name, balance)| Discrepancy {
+                account: name.to_string(),
+                balance: **balance,
+                journal: Cents(replayed.get(name.as_str()).copied().unwrap_or(0)),
+            })
+            .collect();
+
+        // Money the journal moved into an account the bank has never heard of
+        for (account, &journal) in &replayed {
+            if !accounts.contains_key(*account) {
+                discrepancies.push(Discrepancy { account: account.to_string(), balance: Cents(0), journal: Cents(journal) });
+            }
+        }
+
+        if discrepancies.is_empty() { Ok(()) } else { Err(discrepancies) }
+    }
+}
+
+// What every backend can do, so the benchmark runs the same work against each
+trait Banking: Sync {
+    fn deposit(&self, account: &str, amount: Cents) -> Result<Cents, BankError>;
+    fn withdraw(&self, account: &str, amount: Cents) -> Result<Cents, BankError>;
+    fn transfer(&self, from: &str, to: &str, amount: Cents) -> Result<(), BankError>;
+    fn check_balance(&self, account: &str) -> Result<Cents, BankError>;
+}
+
+impl Banking for Bank {
+    fn deposit(&self, account: &str, amount: Cents) -> Result<Cents, BankError> {
+        Bank::deposit(self, account, amount)
+    }
+
+    fn withdraw(&self, account: &str, amount: Cents) -> Result<Cents, BankError> {
+        Bank::withdraw(self, account, amount)
+    }
+
+    fn transfer(&self, from: &str, to: &str, amount: Cents) -> Result<(), BankError> {
+        Bank::transfer(self, from, to, amount)
+    }
+
+    fn check_balance(&self, account: &str) -> Result<Cents, BankError> {
+        Bank::check_balance(self, account)
+    }
+}
+
+/*
+    - Each balance is an AtomicI64 of cents changed with fetch_update (the compare-and-exchange loop from Chapter 2),
+      no thread ever waits on another thread's account
+    - The map's write lock is only for opening accounts, every other operation shares the read lock
+    - A transfer takes the money out first (the part that can fail) then puts it in... nothing is lost or made up,
+      but for a moment it's in neither account and a total added up right then comes out short
+    - No journal, these are the bare balances to measure Bank against
+    - total is every balance plus whatever is mid-transfer. Deposits claim room in it first, and balances are never
+      negative, so no single balance can pass it... a credit can't overflow once the deposit got through
+*/
+struct AtomicBank {
+    accounts: RwLock<HashMap<String, AtomicI64>>,
+    total: AtomicI64,
+}
+
+impl AtomicBank {
+    fn new() -> Self {
+        AtomicBank {
+            accounts: RwLock::new(HashMap::new()),
+            total: AtomicI64::new(0),
+        }
+    }
+
+    fn claim(&self, account: &str, amount: Cents) -> Result<(), BankError> {
+        self.total
+            .fetch_update(Relaxed, Relaxed, |cents| cents.checked_add(amount.0))
+            .map(|_| ())
+            .map_err(|_| BankError::Overflow { account: account.to_string(), amount })
+    }
+
+    // Never lets a balance go below 0, hands back the balance after
+    fn take(account: &str, balance: &AtomicI64, amount: Cents) -> Result<Cents, BankError> {
+        balance
+            .fetch_update(Relaxed, Relaxed, |cents| if cents >= amount.0 { Some(cents - amount.0) } else { None })
+            .map(|before| Cents(before - amount.0))
+            .map_err(|cents| BankError::InsufficientFunds { account: account.to_string(), balance: Cents(cents), wanted: amount })
+    }
+}
+
+impl Banking for AtomicBank {
+    fn deposit(&self, account: &str, amount: Cents) -> Result<Cents, BankError> {
+        check_amount(amount)?;
+        self.claim(account, amount)?;
+
+        if let Some(balance) = self.accounts.read().unwrap_or_else(PoisonError::into_inner).get(account) {
+            return Ok(Cents(balance.fetch_add(amount.0, Relaxed) + amount.0));
+        }
+
+        let mut accounts = self.accounts.write().unwrap_or_else(PoisonError::into_inner);
+        let balance = accounts.entry(account.to_string()).or_insert_with(|| AtomicI64::new(0));
+        Ok(Cents(balance.fetch_add(amount.0, Relaxed) + amount.0))
+    }
+
+    fn withdraw(&self, account: &str, amount: Cents) -> Result<Cents, BankError> {
+        check_amount(amount)?;
+
+        let accounts = self.accounts.read().unwrap_or_else(PoisonError::into_inner);
+        let balance = accounts.get(account).ok_or(BankError::UnknownAccount(account.to_string()))?;
+        let after = AtomicBank::take(account, balance, amount)?;
+        self.total.fetch_sub(amount.0, Relaxed);
+        Ok(after)
+    }
+
+    // Both accounts are looked up before anything moves, once the money is out putting it in can't fail (see total)
+    fn transfer(&self, from: &str, to: &str, amount: Cents) -> Result<(), BankError> {
+        check_amount(amount)?;
+
+        let accounts = self.accounts.read().unwrap_or_else(PoisonError::into_inner);
+        let source = accounts.get(from).ok_or(BankError::UnknownAccount(from.to_string()))?;
+        let target = accounts.get(to).ok_or(BankError::UnknownAccount(to.to_string()))?;
+
+        if from == to {
+            let balance = Cents(source.load(Relaxed));
+            return if balance >= amount {
+                Ok(())
+            } else {
+                Err(BankError::InsufficientFunds { account: from.to_string(), balance, wanted: amount })
+            };
+        }
+
+        AtomicBank::take(from, source, amount)?;
+        target.fetch_add(amount.0, Relaxed);
+        Ok(())
+    }
+
+    fn check_balance(&self, account: &str) -> Result<Cents, BankError> {
+        let accounts = self.accounts.read().unwrap_or_else(PoisonError::into_inner);
+        let balance = accounts.get(account).ok_or(BankError::UnknownAccount(account.to_string()))?;
+        Ok(Cents(balance.load(Relaxed)))
+    }
+}
+
+// The same mix of deposits, withdrawals, transfers and balance checks over 16 accounts from `threads` tellers.
+// Bank also writes every change to its journal (one Mutex for everyone), that's part of what's being measured
+fn bench(name: &str, bank: &impl Banking, threads: usize, ops_per_thread: usize) {
+    let accounts: Vec<String> = (0..16).map(|i| format!("Account{}", i)).collect();
+    for account in &accounts {
+        bank.deposit(account, Cents::dollars(1_000)).unwrap();
+    }
+
+    let started = Instant::now();
+    thread::scope(|s| {
+        for t in 0..threads {
+            let accounts = &accounts;
+            s.spawn(move || {
+                for n in 0..ops_per_thread {
+                    let account = &accounts[(t * 5 + n) % accounts.len()];
+                    let _ = match n % 4 {
+                        0 => bank.deposit(account, Cents(100)).map(|_| ()),
+                        1 => bank.withdraw(account, Cents(100)).map(|_| ()),
+                        2 => bank.transfer(account, &accounts[(t + n * 3) % accounts.len()], Cents(50)),
+                        _ => bank.check_balance(account).map(|_| ()),
+                    };
+                }
+            });
+        }
+    });
+
+    println!("{}: {} threads, {} operations in {:.2?}", name, threads, threads * ops_per_thread, started.elapsed());
+}
+
+fn main() {
+    let bank = Arc::new(Bank::new());
+    let mut handles = vec![];
+
+    for i in 0..5 {
+        let bank_clone = Arc::clone(&bank);
+        let handle = thread::spawn(move || {
+            let account = format!("Account{}", i);
+            bank_clone.deposit(&account, Cents::dollars(100)).unwrap();
+            thread::sleep(Duration::from_millis(100)); // Simulate other work
+            if let Err(e) = bank_clone.withdraw(&account, Cents(30_05)) {
+                println!("{}", e);
+            }
+            match bank_clone.check_balance(&account) {
+                Ok(balance) => println!("{} - Balance: {}", account, balance),
+                Err(e) => println!("{}", e),
+            }
+        });
+        handles.push(handle);
+    }
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    // Everyone pays the next account along, the total stays at 5 * 69.95
+    thread::scope(|s| {
+        for i in 0..5 {
+            let bank = &bank;
+            s.spawn(move || {
+                let (from, to) = (format!("Account{}", i), format!("Account{}", (i + 1) % 5));
+                if let Err(e) = bank.transfer(&from, &to, Cents::dollars(25)) {
+                    println!("{}", e);
+                }
+            });
+        }
+    });
+    let total: i64 = (0..5).filter_map(|i| bank.check_balance(&format!("Account{}", i)).ok()).map(|balance| balance.0).sum();
+    println!("Total after transfers: {}", Cents(total));
+
+    match bank.statement("Account0", 0, u64::MAX) {
+        Ok(statement) => println!("{}", statement),
+        Err(e) => println!("{}", e),
+    }
+    println!("Journal matches balances: {:?}", bank.check_consistency());
+
+    // A report over every account while the tellers keep working, the total can't catch a transfer half done
+    thread::scope(|s| {
+        s.spawn(|| {
+            for n in 0..1_000 {
+                let _ = bank.transfer(&format!("Account{}", n % 5), &format!("Account{}", (n + 2) % 5), Cents(1_99));
+            }
+        });
+        for _ in 0..3 {
+            let snapshot = bank.snapshot();
+            let first = snapshot.balance("Account0").unwrap_or_default();
+            println!("Snapshot of {} journal entries: Account0 {}, total {}", snapshot.entries, first, snapshot.total());
+        }
+    });
+
+    for threads in [1, 4, 8] {
+        bench("rwlock + mutex per account", &Bank::new(), threads, 200_000);
+        bench("atomic per account", &AtomicBank::new(), threads, 200_000);
+    }
+
+    for result in [
+        bank.transfer("Account0", "Nobody", Cents(100)),
+        bank.withdraw("Account1", Cents::dollars(1_000)).map(|_| ()),
+        bank.deposit("Account2", Cents(-5)).map(|_| ()),
+    ] {
+        if let Err(e) = result {
+            println!("{}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn total(bank: &impl Banking, accounts: usize) -> Cents {
+        Cents((0..accounts).map(|i| bank.check_balance(&format!("Account{}", i)).unwrap().0).sum())
+    }
+
+    #[test]
+    fn transfers_conserve_money() {
+        conserves_money(&Bank::new());
+        conserves_money(&AtomicBank::new());
+    }
+
+    fn conserves_money(bank: &impl Banking) {
+        for i in 0..6 {
+            bank.deposit(&format!("Account{}", i), Cents::dollars(1_000)).unwrap();
+        }
+
+        // Some transfers bounce for lack of funds and that's fine
+        thread::scope(|s| {
+            for t in 0..8 {
+                s.spawn(move || {
+                    for n in 0..5_000 {
+                        let from = format!("Account{}", (t + n) % 6);
+                        let to = format!("Account{}", (t * 7 + n * 3 + 1) % 6);
+                        let _ = bank.transfer(&from, &to, Cents(((n % 50) + 1) as i64 * 101));
+                    }
+                });
+            }
+
+            // Totals read in the middle may be torn, but nothing can go negative
+            for _ in 0..1_000 {
+                assert!((0..6).all(|i| bank.check_balance(&format!("Account{}", i)).unwrap() >= Cents(0)));
+            }
+        });
+
+        assert_eq!(total(bank, 6), Cents::dollars(6_000));
+    }
+
+    #[test]
+    fn atomic_balances_behave_like_bank() {
+        let bank = AtomicBank::new();
+        assert_eq!(bank.deposit("A", Cents(10_10)), Ok(Cents(10_10)));
+        bank.deposit("B", Cents(0)).unwrap();
+
+        // 100 tellers each try to take 50c out of $10.10, exactly 20 get it
+        let paid = thread::scope(|s| {
+            let tellers: Vec<_> = (0..100).map(|_| s.spawn(|| bank.transfer("A", "B", Cents(50)).is_ok())).collect();
+            tellers.into_iter().map(|teller| teller.join().unwrap()).filter(|&paid| paid).count()
+        });
+        assert_eq!(paid, 20);
+        assert_eq!(bank.check_balance("A"), Ok(Cents(10)));
+        assert_eq!(bank.check_balance("B"), Ok(Cents::dollars(10)));
+
+        assert_eq!(
+            bank.withdraw("A", Cents(11)),
+            Err(BankError::InsufficientFunds { account: String::from("A"), balance: Cents(10), wanted: Cents(11) })
+        );
+        assert_eq!(bank.transfer("A", "C", Cents(1)), Err(BankError::UnknownAccount(String::from("C"))));
+        assert_eq!(bank.deposit("A", Cents(-1)), Err(BankError::NegativeAmount(Cents(-1))));
+        assert_eq!(bank.check_balance("A"), Ok(Cents(10)));
+    }
+
+    #[test]
+    fn overflowing_amounts_are_refused() {
+        let huge = Cents(i64::MAX - 5);
+
+        let bank = Bank::new();
+        bank.deposit("A", huge).unwrap();
+        bank.deposit("B", Cents(10)).unwrap();
+        assert_eq!(bank.deposit("A", Cents(6)), Err(BankError::Overflow { account: String::from("A"), amount: Cents(6) }));
+        assert_eq!(bank.transfer("B", "A", Cents(6)), Err(BankError::Overflow { account: String::from("A"), amount: Cents(6) }));
+
+        // Nothing moved and A isn't poisoned
+        assert_eq!(bank.check_balance("A"), Ok(huge));
+        assert_eq!(bank.check_balance("B"), Ok(Cents(10)));
+        assert_eq!(bank.check_consistency(), Ok(()));
+
+        // The atomic bank stops it at the deposit, so no balance can wrap round to negative
+        let bank = AtomicBank::new();
+        bank.deposit("A", huge).unwrap();
+        assert_eq!(bank.deposit("B", Cents(6)), Err(BankError::Overflow { account: String::from("B"), amount: Cents(6) }));
+        bank.deposit("B", Cents(5)).unwrap();
+        assert_eq!(bank.check_balance("B"), Ok(Cents(5)));
+        bank.withdraw("A", Cents(10)).unwrap();
+        bank.deposit("B", Cents(6)).unwrap();
+        bank.transfer("B", "A", Cents(11)).unwrap();
+        assert_eq!(bank.check_balance("A"), Ok(Cents(i64::MAX - 4)));
+    }
+
+    #[test]
+    fn opposite_transfers_dont_deadlock() {
+        let bank = Bank::new();
+        bank.deposit("A", Cents::dollars(100)).unwrap();
+        bank.deposit("B", Cents::dollars(100)).unwrap();
+
+        thread::scope(|s| {
+            s.spawn(|| (0..20_000).for_each(|_| { let _ = bank.transfer("A", "B", Cents(100)); }));
+            s.spawn(|| (0..20_000).for_each(|_| { let _ = bank.transfer("B", "A", Cents(100)); }));
+        });
+
+        assert_eq!(Cents(bank.check_balance("A").unwrap().0 + bank.check_balance("B").unwrap().0), Cents::dollars(200));
+        let everything = bank.check_balance("A").unwrap();
+        assert!(bank.transfer("A", "A", everything).is_ok());
+        assert!(bank.transfer("A", "A", Cents(everything.0 + 1)).is_err());
+    }
+
+    #[test]
+    fn callers_are_told_what_went_wrong() {
+        let bank = Bank::new();
+        assert_eq!(bank.deposit("A", Cents(10_10)), Ok(Cents(10_10)));
+
+        assert_eq!(
+            bank.withdraw("A", Cents(10_11)),
+            Err(BankError::InsufficientFunds { account: String::from("A"), balance: Cents(10_10), wanted: Cents(10_11) })
+        );
+        assert_eq!(bank.withdraw("B", Cents(1)), Err(BankError::UnknownAccount(String::from("B"))));
+        assert_eq!(bank.transfer("A", "B", Cents(1)), Err(BankError::UnknownAccount(String::from("B"))));
+        assert_eq!(bank.deposit("A", Cents(-1)), Err(BankError::NegativeAmount(Cents(-1))));
+        assert_eq!(bank.check_balance("B"), Err(BankError::UnknownAccount(String::from("B"))));
+
+        // Nothing above moved any money
+        assert_eq!(bank.check_balance("A"), Ok(Cents(10_10)));
+        assert_eq!(Cents(-1_05).to_string(), "-$1.05");
+    }
+
+    // The test keeps the other end of the Arc to move time along
+    struct ManualClock(Arc<AtomicU64>);
+
+    impl Clock for ManualClock {
+        fn now(&self) -> u64 {
+            self.0.load(Relaxed)
+        }
+    }
+
+    #[test]
+    fn statements_cover_a_date_range() {
+        let clock = Arc::new(AtomicU64::new(100));
+        let bank = Bank::with_clock(Box::new(ManualClock(clock.clone())));
+
+        bank.deposit("A", Cents::dollars(50)).unwrap();
+        bank.deposit("B", Cents::dollars(5)).unwrap();
+        clock.store(200, Relaxed);
+        bank.withdraw("A", Cents::dollars(10)).unwrap();
+        clock.store(300, Relaxed);
+        bank.transfer("B", "A", Cents(2_50)).unwrap();
+        assert!(bank.withdraw("A", Cents::dollars(1_000)).is_err());
+        clock.store(400, Relaxed);
+        bank.deposit("A", Cents(1)).unwrap();
+
+        // [200, 400) skips the first deposit and the last, failures never made it into the journal
+        let statement = bank.statement("A", 200, 400).unwrap();
+        assert_eq!(statement.opening, Cents::dollars(50));
+        assert_eq!(
+            statement.lines,
+            vec![
+                StatementLine { id: 3, at: 200, description: String::from("Withdrawal"), amount: -10_00, balance: Cents::dollars(40) },
+                StatementLine { id: 4, at: 300, description: String::from("Transfer B -> A"), amount: 2_50, balance: Cents(42_50) },
+            ]
+        );
+        assert_eq!(statement.closing, Cents(42_50));
+
+        assert_eq!(bank.statement("A", 0, u64::MAX).unwrap().closing, bank.check_balance("A").unwrap());
+        assert_eq!(bank.statement("B", 0, 300).unwrap().lines.len(), 1);
+        assert_eq!(bank.statement("C", 0, 300), Err(BankError::UnknownAccount(String::from("C"))));
+    }
+
+    #[test]
+    fn journal_replays_to_the_same_balances() {
+        let bank = Bank::new();
+        thread::scope(|s| {
+            for t in 0..6 {
+                let bank = &bank;
+                s.spawn(move || {
+                    for n in 0..2_000 {
+                        let account = format!("Account{}", (t + n) % 4);
+                        let _ = match n % 3 {
+                            0 => bank.deposit(&account, Cents(150)).map(|_| ()),
+                            1 => bank.withdraw(&account, Cents(70)).map(|_| ()),
+                            _ => bank.transfer(&account, &format!("Account{}", (t + n + 1) % 4), Cents(45)),
+                        };
+                    }
+                });
+            }
+
+            // Checking while everything is moving still adds up
+            for _ in 0..50 {
+                assert_eq!(bank.check_consistency(), Ok(()));
+            }
+        });
+        assert_eq!(bank.check_consistency(), Ok(()));
+
+        // Only what went through gets an id, one each with no gaps
+        let ids: Vec<u64> = bank.entries().iter().map(|entry| entry.id).collect();
+        assert_eq!(ids, (1..=ids.len() as u64).collect::<Vec<_>>());
+
+        // Money that appears out of nowhere is caught
+        let before = bank.check_balance("Account2").unwrap();
+        *bank.accounts.read().unwrap()["Account2"].lock().unwrap() = Cents(before.0 + 1);
+        assert_eq!(
+            bank.check_consistency(),
+            Err(vec![Discrepancy { account: String::from("Account2"), balance: Cents(before.0 + 1), journal: before }])
+        );
+    }
+
+    #[test]
+    fn snapshots_never_see_half_a_transfer() {
+        let bank = Bank::new();
+        for i in 0..5 {
+            bank.deposit(&format!("Account{}", i), Cents::dollars(100)).unwrap();
+        }
+        let before = bank.snapshot();
+
+        thread::scope(|s| {
+            for t in 0..4 {
+                let bank = &bank;
+                s.spawn(move || {
+                    for n in 0..5_000 {
+                        let _ = bank.transfer(&format!("Account{}", (t + n) % 5), &format!("Account{}", (t + n * 2 + 1) % 5), Cents(3_33));
+                    }
+                });
+            }
+
+            // Adding up check_balance one at a time could land between the two halves of a transfer, these can't
+            let mut entries = 0;
+            for _ in 0..500 {
+                let snapshot = bank.snapshot();
+                assert_eq!(snapshot.total(), Cents::dollars(500));
+                assert!(snapshot.entries >= entries);
+                entries = snapshot.entries;
+            }
+        });
+
+        // Once everything has stopped the snapshot is the same as the balances
+        let after = bank.snapshot();
+        for i in 0..5 {
+            let account = format!("Account{}", i);
+            assert_eq!(after.balance(&account), Some(bank.check_balance(&account).unwrap()));
+        }
+
+        // The first one still shows the moment it was taken
+        assert_eq!(before.entries, 5);
+        assert!((0..5).all(|i| before.balance(&format!("Account{}", i)) == Some(Cents::dollars(100))));
+        assert!(Arc::ptr_eq(&after, &bank.snapshot()));
+    }
+
+    #[test]
+    fn poisoned_accounts_are_recovered_when_they_can_be() {
+        let bank = Bank::new();
+        bank.deposit("A", Cents::dollars(5)).unwrap();
+        bank.deposit("B", Cents::dollars(5)).unwrap();
+
+        let poison = |account: &str, balance: Option<Cents>| {
+            let accounts = bank.accounts.read().unwrap();
+            let lock = accounts.get(account).unwrap();
+            thread::scope(|s| {
+                let panicked = s.spawn(|| {
+                    let mut guard = lock.lock().unwrap();
+                    if let Some(balance) = balance {
+                        *guard = balance;
+                    }
+                    panic!("teller walked off");
+                });
+                assert!(panicked.join().is_err());
+            });
+            assert!(lock.is_poisoned());
+        };
+
+        // Nothing was half written, so A carries on as normal
+        poison("A", None);
+        assert_eq!(bank.deposit("A", Cents(1)), Ok(Cents(5_01)));
+
+        // B was left negative, that can't be trusted
+        poison("B", Some(Cents(-1)));
+        assert_eq!(bank.withdraw("B", Cents(1)), Err(BankError::LockPoisoned(String::from("B"))));
+        assert_eq!(bank.transfer("A", "B", Cents(1)), Err(BankError::LockPoisoned(String::from("B"))));
+        assert_eq!(bank.check_balance("A"), Ok(Cents(5_01)));
+    }
+}
@@ -1,95 +1,2343 @@
 // Chapter 5 Code
-//// Modeled after my Leetcode post for 1603: Designing Parking Systems
+// Modeled after my Leetcode post for 1603: Designing Parking Systems
 
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cell::Cell;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, BinaryHeap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::sync::atomic::{AtomicBool, AtomicI32};
 use std::{thread, time};
 
+// Bigger spots fit smaller cars, never the other way around
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum CarSize {
+    Big,
+    Medium,
+    Small,
+}
+
+impl CarSize {
+    const ALL: [CarSize; 3] = [CarSize::Big, CarSize::Medium, CarSize::Small];
+
+    fn index(&self) -> usize {
+        match self {
+            CarSize::Big => 0,
+            CarSize::Medium => 1,
+            CarSize::Small => 2,
+        }
+    }
+
+    // Spot sizes this car fits in, its own first and working up to big
+    fn fitting(&self) -> impl Iterator<Item = CarSize> {
+        CarSize::ALL[..=self.index()].iter().rev().copied()
+    }
+}
+
+impl fmt::Display for CarSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CarSize::Big => write!(f, "big"),
+            CarSize::Medium => write!(f, "medium"),
+            CarSize::Small => write!(f, "small"),
+        }
+    }
+}
+
+// Accepts the names as well as the old 1/2/3 codes from the Leetcode version
+impl FromStr for CarSize {
+    type Err = ParkingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "big" | "1" => Ok(CarSize::Big),
+            "medium" | "2" => Ok(CarSize::Medium),
+            "small" | "3" => Ok(CarSize::Small),
+            _ => Err(ParkingError::UnsupportedSize(s.to_string())),
+        }
+    }
+}
+
+impl TryFrom<i32> for CarSize {
+    type Error = ParkingError;
+
+    fn try_from(car_type: i32) -> Result<Self, Self::Error> {
+        match car_type {
+            1 => Ok(CarSize::Big),
+            2 => Ok(CarSize::Medium),
+            3 => Ok(CarSize::Small),
+            _ => Err(ParkingError::UnsupportedSize(car_type.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum ParkingError {
+    NegativeCapacity(i32),
+    UnsupportedSize(String),
+    InvalidSpot(String),
+    UnknownSpot(SpotId),
+    SpotAlreadyEmpty(SpotId),
+    SpotInUse(SpotId),
+    WrongTicket(SpotId),
+    BadSimulation(String),
+    Io(String),
+    CorruptState(String),
+}
+
+impl fmt::Display for ParkingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParkingError::NegativeCapacity(capacity) => write!(f, "{} isn't real", capacity),
+            ParkingError::UnsupportedSize(size) => write!(f, "{} isn't supported", size),
+            ParkingError::InvalidSpot(spot) => write!(f, "{} isn't a spot, try something like L1-S-01", spot),
+            ParkingError::UnknownSpot(spot) => write!(f, "{} isn't in this lot", spot),
+            ParkingError::SpotAlreadyEmpty(spot) => write!(f, "{} is already empty", spot),
+            ParkingError::SpotInUse(spot) => write!(f, "{} still has a car in it", spot),
+            ParkingError::WrongTicket(spot) => write!(f, "that ticket isn't for the car in {}", spot),
+            ParkingError::BadSimulation(why) => write!(f, "can't simulate that: {}", why),
+            ParkingError::Io(e) => write!(f, "couldn't reach the lot's files: {}", e),
+            ParkingError::CorruptState(e) => write!(f, "saved lot state is unreadable: {}", e),
+        }
+    }
+}
+
+impl Error for ParkingError {}
+
+impl From<io::Error> for ParkingError {
+    fn from(e: io::Error) -> Self {
+        ParkingError::Io(e.to_string())
+    }
+}
+
+// Where a car ended up: level, spot size and the spot number within that size
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct SpotId {
+    level: usize,
+    car_type: CarSize,
+    number: usize,
+}
+
+impl fmt::Display for SpotId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let class = match self.car_type {
+            CarSize::Big => 'B',
+            CarSize::Medium => 'M',
+            CarSize::Small => 'S',
+        };
+        write!(f, "L{}-{}-{:02}", self.level + 1, class, self.number + 1)
+    }
+}
+
+// Reads back what Display prints, e.g. L2-M-07
+impl FromStr for SpotId {
+    type Err = ParkingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParkingError::InvalidSpot(s.to_string());
+        let parts: Vec<&str> = s.trim().split('-').collect();
+
+        if parts.len() != 3 || !parts[0].starts_with(['L', 'l']) {
+            return Err(invalid());
+        }
+
+        let level: usize = parts[0][1..].parse().map_err(|_| invalid())?;
+        let number: usize = parts[2].parse().map_err(|_| invalid())?;
+        let car_type = match parts[1] {
+            "B" | "b" => CarSize::Big,
+            "M" | "m" => CarSize::Medium,
+            "S" | "s" => CarSize::Small,
+            _ => return Err(invalid()),
+        };
+
+        if level == 0 || number == 0 {
+            return Err(invalid());
+        }
+
+        Ok(SpotId { level: level - 1, car_type, number: number - 1 })
+    }
+}
+
+// Handed out on the way in, handed back on the way out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Ticket {
+    spot: SpotId,
+    car_type: CarSize,
+    arrival: u64, // seconds since the UNIX epoch
+}
+
 struct ParkingSpots {
-    car_type: i32,
+    car_type: CarSize,
     capacity: i32,
     taken: i32,
-    car_type_str: String
+    occupied: Vec<bool>,
+    verbose: bool,
 }
 
 impl ParkingSpots {
-    fn new(car_type: i32, capacity: i32) -> Result<Self, String> {
+    fn new(car_type: CarSize, capacity: i32) -> Result<Self, ParkingError> {
         if capacity < 0 {
-            return Err(format!("{} isn't real", capacity));
+            return Err(ParkingError::NegativeCapacity(capacity));
         }
-        if ![1, 2, 3].contains(&car_type) {
-            return Err(format!("{} isn't supported", car_type));
-        }
-        
-        let car_type_str = match car_type {
-            1 => String::from("big"),
-            2 => String::from("medium"),
-            3 => String::from("small"),
-            _ => String::from("unknown"), // Handle any other values gracefully
-        };
         
         Ok(Self {
             car_type,
             capacity,
             taken: 0,
-            car_type_str
+            occupied: vec![false; capacity as usize],
+            verbose: true,
         })
     }
 
-    fn add_car(&mut self) -> bool {
+    // Hands back the first free spot number, lowest first
+    fn add_car(&mut self) -> Option<usize> {
         if self.taken < self.capacity {
+            let number = self.occupied.iter().position(|&spot| !spot)?;
+            self.occupied[number] = true;
             self.taken += 1;
-            self.display();
-            true
+            if self.verbose {
+                self.display();
+            }
+            Some(number)
         } else {
-            false
+            None
+        }
+    }
+
+    fn remove_car(&mut self, number: usize) -> bool {
+        match self.occupied.get_mut(number) {
+            Some(spot) if *spot => {
+                *spot = false;
+                self.taken -= 1;
+                if self.verbose {
+                    self.display();
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Puts a car back in a known spot, used when undoing a departure and when replaying the journal
+    fn add_back(&mut self, number: usize) -> bool {
+        match self.occupied.get_mut(number) {
+            Some(spot) if !*spot => {
+                *spot = true;
+                self.taken += 1;
+                true
+            }
+            _ => false,
         }
     }
+
+    fn free(&self) -> i32 {
+        self.capacity - self.taken
+    }
     
     fn display(&self) {
-        println!("{} ({}) has a capacity of {} and is currently occupied with {} cars!", self.car_type_str, self.car_type, self.capacity, self.taken);
+        println!("{} has a capacity of {} and is currently occupied with {} cars!", self.car_type, self.capacity, self.taken);
+    }
+}
+
+// One floor of the lot: big, medium and small spots in that order
+struct Level {
+    lot: Vec<ParkingSpots>,
+}
+
+impl Level {
+    fn new(big: i32, medium: i32, small: i32) -> Result<Self, ParkingError> {
+        let lot = vec![
+            ParkingSpots::new(CarSize::Big, big)?,
+            ParkingSpots::new(CarSize::Medium, medium)?,
+            ParkingSpots::new(CarSize::Small, small)?,
+        ];
+
+        Ok(Self { lot })
+    }
+
+    fn spots(&self, car_type: CarSize) -> &ParkingSpots {
+        &self.lot[car_type.index()]
+    }
+
+    fn spots_mut(&mut self, car_type: CarSize) -> &mut ParkingSpots {
+        &mut self.lot[car_type.index()]
+    }
+
+    fn has_room(&self, car_type: CarSize) -> bool {
+        self.spots(car_type).free() > 0
     }
 }
 
 ////////////////////////////
-/////// Client Code ////////
+////////// Clocks //////////
 ////////////////////////////
 
-struct ParkingSystem {
-    lot: Vec<ParkingSpots>,
+// Where the lot gets "now" from (seconds since the UNIX epoch)... swap in a ManualClock to make tests deterministic
+trait Clock {
+    fn now(&self) -> u64;
 }
 
-impl ParkingSystem {
+struct SystemClock;
 
-    fn new(big: i32, medium: i32, small: i32) -> Self {
-        let lot = vec![
-            ParkingSpots::new(1, big).unwrap(),
-            ParkingSpots::new(2, medium).unwrap(),
-            ParkingSpots::new(3, small).unwrap(),
-        ];
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+// Clones share the same time, so keep one around to move the lot's clock forward
+#[derive(Clone, Default)]
+struct ManualClock {
+    now: Rc<Cell<u64>>,
+}
 
-        Self { lot }
+impl ManualClock {
+    fn new(start: u64) -> Self {
+        Self { now: Rc::new(Cell::new(start)) }
     }
-    
-    fn add_car(&mut self, car_type: i32) -> bool {
-        self.lot[(car_type - 1) as usize].add_car()
+
+    #[cfg(test)]
+    fn advance(&self, secs: u64) {
+        self.now.set(self.now.get() + secs);
+    }
+
+    fn set(&self, secs: u64) {
+        self.now.set(secs);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.now.get()
+    }
+}
+
+// Always the same moment, and Send + Sync so the gate threads can share it
+struct FixedClock(u64);
+
+impl Clock for FixedClock {
+    fn now(&self) -> u64 {
+        self.0
+    }
+}
+
+////////////////////////////
+// Allocation Policies /////
+////////////////////////////
+
+// Decides which (level, spot size) a car is sent to... None turns it away
+trait AllocationPolicy {
+    fn name(&self) -> &str;
+    fn choose(&self, car_type: CarSize, levels: &[Level]) -> Option<(usize, CarSize)>;
+}
+
+// The original behaviour: exact class or nothing
+struct Strict;
+
+impl AllocationPolicy for Strict {
+    fn name(&self) -> &str {
+        "strict"
+    }
+
+    fn choose(&self, car_type: CarSize, levels: &[Level]) -> Option<(usize, CarSize)> {
+        levels
+            .iter()
+            .position(|floor| floor.has_room(car_type))
+            .map(|level| (level, car_type))
+    }
+}
+
+// Walks the levels bottom up and takes a bigger spot on the current level before moving up a floor
+struct UpgradeToLarger;
+
+impl AllocationPolicy for UpgradeToLarger {
+    fn name(&self) -> &str {
+        "upgrade-to-larger"
+    }
+
+    fn choose(&self, car_type: CarSize, levels: &[Level]) -> Option<(usize, CarSize)> {
+        levels.iter().enumerate().find_map(|(level, floor)| {
+            car_type.fitting()
+                .find(|&class| floor.has_room(class))
+                .map(|class| (level, class))
+        })
+    }
+}
+
+// Only gives up a bigger spot once the exact class is full on every level
+struct BestFit;
+
+impl AllocationPolicy for BestFit {
+    fn name(&self) -> &str {
+        "best-fit"
+    }
+
+    fn choose(&self, car_type: CarSize, levels: &[Level]) -> Option<(usize, CarSize)> {
+        car_type.fitting().find_map(|class| {
+            levels
+                .iter()
+                .position(|floor| floor.has_room(class))
+                .map(|level| (level, class))
+        })
+    }
+}
+
+// Everything the lot decided, in order
+#[derive(Debug, Clone, PartialEq)]
+enum Event {
+    Parked { ticket: Ticket, policy: String },
+    Rejected { car_type: CarSize, policy: String },
+    Left { ticket: Ticket },
+    SnapshotFailed(String), // the change is in the journal and stands, the snapshot is tried again on the next one
+}
+
+////////////////////////////
+////////// Billing /////////
+////////////////////////////
+
+const HOUR: u64 = 60 * 60;
+const DAY: u64 = 24 * HOUR;
+
+// Money is kept in cents so receipts add up exactly
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+struct Cents(u64);
+
+impl fmt::Display for Cents {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "${}.{:02}", self.0 / 100, self.0 % 100)
+    }
+}
+
+// Hourly rates are indexed by CarSize::index(), every started hour is charged in full
+#[derive(Debug, Clone)]
+struct TariffSchedule {
+    day_rates: [Cents; 3],
+    night_rates: [Cents; 3],
+    night_start: u64, // hour of the day (UTC) the night rate kicks in
+    night_end: u64,   // hour of the day (UTC) it stops
+    grace_period: u64, // seconds, stays this short are free
+    daily_max: [Cents; 3],
+}
+
+impl TariffSchedule {
+    // Parking for nothing... what the lot charges until someone sets a real tariff
+    fn free() -> Self {
+        Self {
+            day_rates: [Cents(0); 3],
+            night_rates: [Cents(0); 3],
+            night_start: 0,
+            night_end: 0,
+            grace_period: 0,
+            daily_max: [Cents(0); 3],
+        }
+    }
+
+    fn is_night(&self, secs: u64) -> bool {
+        let hour = (secs % DAY) / HOUR;
+
+        if self.night_start <= self.night_end {
+            hour >= self.night_start && hour < self.night_end
+        } else {
+            hour >= self.night_start || hour < self.night_end
+        }
+    }
+
+    // One line per calendar day: day and night hours at their rates, trimmed to the daily maximum
+    fn bill(&self, ticket: &Ticket, departure: u64) -> Receipt {
+        let size = ticket.car_type.index();
+        let stay = departure.saturating_sub(ticket.arrival);
+        let mut lines = Vec::new();
+
+        if stay <= self.grace_period {
+            lines.push(ReceiptLine {
+                description: format!("Grace period ({} min)", stay / 60),
+                amount: Cents(0),
+            });
+        } else {
+            // (day, day hours, night hours) for every calendar day the car was parked
+            let mut days: Vec<(u64, u64, u64)> = Vec::new();
+            let mut block = ticket.arrival;
+
+            while block < departure {
+                let day = block / DAY;
+                if days.last().is_none_or(|&(last, _, _)| last != day) {
+                    days.push((day, 0, 0));
+                }
+
+                let entry = days.last_mut().unwrap();
+                if self.is_night(block) {
+                    entry.2 += 1;
+                } else {
+                    entry.1 += 1;
+                }
+
+                block += HOUR;
+            }
+
+            for (n, &(_, day_hours, night_hours)) in days.iter().enumerate() {
+                let day_charge = self.day_rates[size].0 * day_hours;
+                let night_charge = self.night_rates[size].0 * night_hours;
+                let charge = Cents(day_charge + night_charge);
+                let capped = charge.min(self.daily_max[size]);
+
+                let mut description = format!(
+                    "Day {}: {}h @ {} + {}h night @ {}",
+                    n + 1,
+                    day_hours,
+                    self.day_rates[size],
+                    night_hours,
+                    self.night_rates[size],
+                );
+                if capped < charge {
+                    description.push_str(&format!(" (capped from {})", charge));
+                }
+
+                lines.push(ReceiptLine { description, amount: capped });
+            }
+        }
+
+        let total = Cents(lines.iter().map(|line| line.amount.0).sum());
+
+        Receipt {
+            ticket: *ticket,
+            departure,
+            lines,
+            total,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct ReceiptLine {
+    description: String,
+    amount: Cents,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Receipt {
+    ticket: Ticket,
+    departure: u64,
+    lines: Vec<ReceiptLine>,
+    total: Cents,
+}
+
+impl fmt::Display for Receipt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Spot {} ({} car)", self.ticket.spot, self.ticket.car_type)?;
+        writeln!(f, "Stayed {} min", (self.departure.saturating_sub(self.ticket.arrival)) / 60)?;
+        for line in &self.lines {
+            writeln!(f, "  {:<50} {:>10}", line.description, line.amount.to_string())?;
+        }
+        write!(f, "  {:<50} {:>10}", "Total", self.total.to_string())
+    }
+}
+
+////////////////////////////
+/////// Persistence ////////
+////////////////////////////
+
+const SNAPSHOT_FILE: &str = "snapshot.json";
+const JOURNAL_FILE: &str = "journal.ndjson";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum JournalEntry {
+    Park(Ticket),
+    Leave(Ticket),
+}
+
+impl JournalEntry {
+    fn ticket(&self) -> Ticket {
+        match *self {
+            JournalEntry::Park(ticket) | JournalEntry::Leave(ticket) => ticket,
+        }
+    }
+
+    fn to_json(self, seq: u64) -> String {
+        let op = match self {
+            JournalEntry::Park(_) => "park",
+            JournalEntry::Leave(_) => "leave",
+        };
+
+        format!("{{\"seq\":{},\"op\":\"{}\",\"ticket\":{}}}", seq, op, ticket_json(&self.ticket()))
+    }
+
+    fn parse(line: &str) -> Result<(u64, Self), ParkingError> {
+        let json = Json::parse(line)?;
+        let ticket = parse_ticket(json.get("ticket")?)?;
+
+        let entry = match json.get("op")?.as_str()? {
+            "park" => JournalEntry::Park(ticket),
+            "leave" => JournalEntry::Leave(ticket),
+            op => return Err(ParkingError::CorruptState(format!("unknown journal op {}", op))),
+        };
+
+        Ok((json.get("seq")?.as_u64()?, entry))
+    }
+}
+
+fn ticket_json(ticket: &Ticket) -> String {
+    format!(
+        "{{\"level\":{},\"spot_size\":\"{}\",\"number\":{},\"car\":\"{}\",\"arrival\":{}}}",
+        ticket.spot.level, ticket.spot.car_type, ticket.spot.number, ticket.car_type, ticket.arrival
+    )
+}
+
+fn parse_ticket(json: &Json) -> Result<Ticket, ParkingError> {
+    Ok(Ticket {
+        spot: SpotId {
+            level: json.get("level")?.as_u64()? as usize,
+            car_type: json.get("spot_size")?.as_str()?.parse()?,
+            number: json.get("number")?.as_u64()? as usize,
+        },
+        car_type: json.get("car")?.as_str()?.parse()?,
+        arrival: json.get("arrival")?.as_u64()?,
+    })
+}
+
+// Append-only, one JSON object per line, flushed before the lot moves on
+struct Journal {
+    path: PathBuf,
+    file: File,
+    seq: u64,
+    snapshotted: u64, // last seq that made it into a snapshot, the journal only holds what came after
+    snapshot_every: u64,
+}
+
+impl Journal {
+    fn open(dir: &Path, snapshot_every: u64, seq: u64) -> Result<Self, ParkingError> {
+        let file = OpenOptions::new().create(true).append(true).open(dir.join(JOURNAL_FILE))?;
+
+        Ok(Self {
+            path: dir.to_path_buf(),
+            file,
+            seq,
+            snapshotted: seq,
+            snapshot_every,
+        })
+    }
+
+    // Returns true when it's time for a fresh snapshot... still true after one failed, so it's retried
+    fn append(&mut self, entry: &JournalEntry) -> Result<bool, ParkingError> {
+        let seq = self.seq + 1;
+        let len = self.file.metadata()?.len();
+
+        // A line that didn't sync is cut back off so the caller can undo the change... if even that fails the line
+        // is there for recovery to replay, so it stands and the caller is told it went in
+        if let Err(e) = writeln!(self.file, "{}", entry.to_json(seq)).and_then(|_| self.file.sync_data()) {
+            if self.file.set_len(len).is_ok() {
+                return Err(e.into());
+            }
+        }
+        self.seq = seq;
+
+        Ok(self.snapshot_every > 0 && seq - self.snapshotted >= self.snapshot_every)
+    }
+
+    // Only once the snapshot holding every entry is safely in place. A crash before this just leaves entries
+    // recovery skips anyway, since they're not newer than the snapshot
+    fn compact(&mut self) -> Result<(), ParkingError> {
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        self.snapshotted = self.seq;
+
+        Ok(())
+    }
+}
+
+// Just enough JSON for snapshots and journal lines: no escapes, no floats
+#[derive(Debug, Clone, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(u64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn parse(input: &str) -> Result<Json, ParkingError> {
+        let mut chars = input.trim().chars().peekable();
+        let value = Self::parse_value(&mut chars)?;
+
+        match chars.next() {
+            None => Ok(value),
+            Some(c) => Err(ParkingError::CorruptState(format!("unexpected {} after the end", c))),
+        }
+    }
+
+    fn parse_value(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<Json, ParkingError> {
+        let corrupt = |what: &str| ParkingError::CorruptState(what.to_string());
+
+        match chars.next() {
+            Some('{') => {
+                let mut fields = Vec::new();
+                if chars.peek() == Some(&'}') {
+                    chars.next();
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    let key = match Self::parse_value(chars)? {
+                        Json::Str(key) => key,
+                        _ => return Err(corrupt("object keys must be strings")),
+                    };
+                    if chars.next() != Some(':') {
+                        return Err(corrupt("missing :"));
+                    }
+                    fields.push((key, Self::parse_value(chars)?));
+
+                    match chars.next() {
+                        Some(',') => continue,
+                        Some('}') => return Ok(Json::Object(fields)),
+                        _ => return Err(corrupt("unterminated object")),
+                    }
+                }
+            }
+            Some('[') => {
+                let mut items = Vec::new();
+                if chars.peek() == Some(&']') {
+                    chars.next();
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(Self::parse_value(chars)?);
+
+                    match chars.next() {
+                        Some(',') => continue,
+                        Some(']') => return Ok(Json::Array(items)),
+                        _ => return Err(corrupt("unterminated array")),
+                    }
+                }
+            }
+            Some('"') => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => return Ok(Json::Str(text)),
+                        Some(c) => text.push(c),
+                        None => return Err(corrupt("unterminated string")),
+                    }
+                }
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let mut digits = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if !c.is_ascii_digit() {
+                        break;
+                    }
+                    digits.push(c);
+                    chars.next();
+                }
+                digits.parse().map(Json::Number).map_err(|_| corrupt("number out of range"))
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                let mut word = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if !c.is_ascii_alphabetic() {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                match word.as_str() {
+                    "true" => Ok(Json::Bool(true)),
+                    "false" => Ok(Json::Bool(false)),
+                    "null" => Ok(Json::Null),
+                    _ => Err(ParkingError::CorruptState(format!("unknown literal {}", word))),
+                }
+            }
+            _ => Err(corrupt("unexpected end of input")),
+        }
+    }
+
+    fn get(&self, key: &str) -> Result<&Json, ParkingError> {
+        match self {
+            Json::Object(fields) => fields
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value)
+                .ok_or(ParkingError::CorruptState(format!("missing {}", key))),
+            _ => Err(ParkingError::CorruptState(format!("expected an object holding {}", key))),
+        }
+    }
+
+    fn as_u64(&self) -> Result<u64, ParkingError> {
+        match self {
+            Json::Number(n) => Ok(*n),
+            _ => Err(ParkingError::CorruptState(String::from("expected a number"))),
+        }
+    }
+
+    fn as_bool(&self) -> Result<bool, ParkingError> {
+        match self {
+            Json::Bool(b) => Ok(*b),
+            _ => Err(ParkingError::CorruptState(String::from("expected true or false"))),
+        }
+    }
+
+    fn as_str(&self) -> Result<&str, ParkingError> {
+        match self {
+            Json::Str(text) => Ok(text),
+            _ => Err(ParkingError::CorruptState(String::from("expected a string"))),
+        }
+    }
+
+    fn as_array(&self) -> Result<&[Json], ParkingError> {
+        match self {
+            Json::Array(items) => Ok(items),
+            _ => Err(ParkingError::CorruptState(String::from("expected an array"))),
+        }
     }
 }
+
 ////////////////////////////
 /////// Client Code ////////
 ////////////////////////////
 
-fn main() {
-    // Pun intended
-    let mut big_lots = ParkingSystem::new(5, 2, 1);
-    
-    // Lets see if it fails!!! Shouldn't lol
-    for _ in 0..8 {
-        let random_num = rand::thread_rng().gen_range(1..=3);
-        println!("{} generated!", random_num);
-        
-        big_lots.add_car(random_num);
-        
-        let one_sec = time::Duration::new(1, 0);
-        thread::sleep(one_sec);
+struct ParkingSystem {
+    levels: Vec<Level>,
+    policy: Box<dyn AllocationPolicy>,
+    events: Vec<Event>,
+    tariff: TariffSchedule,
+    clock: Box<dyn Clock>,
+    journal: Option<Journal>,
+    parked: BTreeMap<SpotId, Ticket>,
+}
+
+impl ParkingSystem {
+
+    fn new(big: i32, medium: i32, small: i32) -> Self {
+        Self::try_new(big, medium, small).unwrap()
+    }
+
+    fn try_new(big: i32, medium: i32, small: i32) -> Result<Self, ParkingError> {
+        Self::try_with_levels(&[(big, medium, small)])
+    }
+
+    fn with_levels(levels: &[(i32, i32, i32)]) -> Self {
+        Self::try_with_levels(levels).unwrap()
+    }
+
+    // One (big, medium, small) tuple per floor, ground floor first
+    fn try_with_levels(levels: &[(i32, i32, i32)]) -> Result<Self, ParkingError> {
+        let levels = levels
+            .iter()
+            .map(|&(big, medium, small)| Level::new(big, medium, small))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            levels,
+            policy: Box::new(Strict),
+            events: Vec::new(),
+            tariff: TariffSchedule::free(),
+            clock: Box::new(SystemClock),
+            journal: None,
+            parked: BTreeMap::new(),
+        })
+    }
+
+    fn set_policy(&mut self, policy: Box<dyn AllocationPolicy>) {
+        self.policy = policy;
+    }
+
+    fn set_tariff(&mut self, tariff: TariffSchedule) {
+        self.tariff = tariff;
+    }
+
+    fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    // Turn off the per-car printing for long simulations
+    fn set_verbose(&mut self, verbose: bool) {
+        for spots in self.levels.iter_mut().flat_map(|floor| floor.lot.iter_mut()) {
+            spots.verbose = verbose;
+        }
+    }
+    
+    // The policy picks the level and spot size, the lot just hands out the spot
+    fn add_car(&mut self, car_type: CarSize) -> Option<Ticket> {
+        let chosen = self.policy.choose(car_type, &self.levels);
+        let policy = self.policy.name().to_string();
+
+        let arrival = self.clock.now();
+
+        let ticket = chosen.and_then(|(level, class)| {
+            let number = self.levels[level].spots_mut(class).add_car()?;
+
+            Some(Ticket {
+                spot: SpotId { level, car_type: class, number },
+                car_type,
+                arrival,
+            })
+        });
+
+        // Nothing counts until it's in the journal... if that fails the car is turned away
+        let ticket = ticket.and_then(|ticket| {
+            self.parked.insert(ticket.spot, ticket);
+
+            if self.record(JournalEntry::Park(ticket)).is_err() {
+                self.parked.remove(&ticket.spot);
+                self.levels[ticket.spot.level].spots_mut(ticket.spot.car_type).remove_car(ticket.spot.number);
+                return None;
+            }
+
+            Some(ticket)
+        });
+
+        match ticket {
+            Some(ticket) => self.events.push(Event::Parked { ticket, policy }),
+            None => self.events.push(Event::Rejected { car_type, policy }),
+        }
+
+        ticket
+    }
+
+    fn remove_car(&mut self, ticket: Ticket) -> Result<(), ParkingError> {
+        let spot = ticket.spot;
+
+        let spots = self
+            .levels
+            .get_mut(spot.level)
+            .map(|floor| floor.spots_mut(spot.car_type))
+            .ok_or(ParkingError::UnknownSpot(spot))?;
+
+        if spot.number >= spots.occupied.len() {
+            return Err(ParkingError::UnknownSpot(spot));
+        }
+        if !spots.occupied[spot.number] {
+            return Err(ParkingError::SpotAlreadyEmpty(spot));
+        }
+
+        // An old ticket for the spot mustn't throw out whoever parked there since
+        if self.parked.get(&spot) != Some(&ticket) {
+            return Err(ParkingError::WrongTicket(spot));
+        }
+
+        spots.remove_car(spot.number);
+        let parked = self.parked.remove(&spot);
+
+        if let Err(e) = self.record(JournalEntry::Leave(ticket)) {
+            self.levels[spot.level].spots_mut(spot.car_type).add_back(spot.number);
+            if let Some(parked) = parked {
+                self.parked.insert(spot, parked);
+            }
+            return Err(e);
+        }
+
+        self.events.push(Event::Left { ticket });
+        Ok(())
+    }
+
+    // The ticket for whoever is parked in a spot, so a lost ticket can be looked up by spot
+    fn ticket_at(&self, spot: SpotId) -> Option<Ticket> {
+        self.parked.get(&spot).copied()
+    }
+
+    // Grows or shrinks one size class on one level... shrinking only drops spots nobody is parked in
+    fn resize(&mut self, level: usize, car_type: CarSize, capacity: i32) -> Result<(), ParkingError> {
+        if capacity < 0 {
+            return Err(ParkingError::NegativeCapacity(capacity));
+        }
+
+        let spots = self
+            .levels
+            .get_mut(level)
+            .map(|floor| floor.spots_mut(car_type))
+            .ok_or(ParkingError::UnknownSpot(SpotId { level, car_type, number: 0 }))?;
+
+        if let Some(number) = spots.occupied.iter().skip(capacity as usize).position(|&taken| taken) {
+            return Err(ParkingError::SpotInUse(SpotId { level, car_type, number: capacity as usize + number }));
+        }
+
+        let before = (spots.occupied.clone(), spots.capacity);
+        spots.occupied.resize(capacity as usize, false);
+        spots.capacity = capacity;
+
+        // The journal only knows about cars, so the new layout has to be in a snapshot before anyone parks in it...
+        // until the rename nothing on disk has changed, so a failed write puts the old layout back
+        if let Err(e) = self.write_snapshot() {
+            let spots = self.levels[level].spots_mut(car_type);
+            (spots.occupied, spots.capacity) = before;
+            return Err(e);
+        }
+
+        // The snapshot is in place, entries left in the journal are older than it and recovery skips them
+        if let Some(Err(e)) = self.journal.as_mut().map(Journal::compact) {
+            self.events.push(Event::SnapshotFailed(e.to_string()));
+        }
+
+        Ok(())
+    }
+
+    // Frees the spot and charges for the stay at the current tariff
+    fn leave(&mut self, ticket: Ticket) -> Result<Receipt, ParkingError> {
+        self.remove_car(ticket)?;
+
+        Ok(self.tariff.bill(&ticket, self.clock.now()))
+    }
+
+    fn free_spots(&self, level: usize, car_type: CarSize) -> Option<i32> {
+        self.levels.get(level).map(|floor| floor.spots(car_type).free())
+    }
+
+    // Free (big, medium, small) spots for every level, ground floor first
+    fn free_spots_per_level(&self) -> Vec<(i32, i32, i32)> {
+        self.levels
+            .iter()
+            .map(|floor| (floor.lot[0].free(), floor.lot[1].free(), floor.lot[2].free()))
+            .collect()
+    }
+
+    // Share of all spots on all levels that are taken right now
+    fn utilisation(&self) -> f64 {
+        let (taken, capacity) = self
+            .levels
+            .iter()
+            .flat_map(|floor| floor.lot.iter())
+            .fold((0, 0), |(taken, capacity), spots| (taken + spots.taken, capacity + spots.capacity));
+
+        if capacity == 0 {
+            0.0
+        } else {
+            taken as f64 / capacity as f64
+        }
+    }
+
+    fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    // Starts journaling into dir and writes a first snapshot... another one follows every `snapshot_every` entries
+    fn enable_persistence(&mut self, dir: &Path, snapshot_every: u64) -> Result<(), ParkingError> {
+        fs::create_dir_all(dir)?;
+        let journal = Journal::open(dir, snapshot_every, 0)?;
+        fs::write(journal.path.join(JOURNAL_FILE), "")?;
+
+        self.journal = Some(journal);
+        self.save_snapshot()
+    }
+
+    // Only a failed append is an error for the caller to undo... once the entry is in the journal recovery will
+    // replay it, so a snapshot that doesn't work out is logged and the change stands
+    fn record(&mut self, entry: JournalEntry) -> Result<(), ParkingError> {
+        let due = match self.journal.as_mut() {
+            Some(journal) => journal.append(&entry)?,
+            None => return Ok(()),
+        };
+
+        if due {
+            if let Err(e) = self.save_snapshot() {
+                self.events.push(Event::SnapshotFailed(e.to_string()));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Then the journal is emptied since everything in it is in the snapshot now
+    fn save_snapshot(&mut self) -> Result<(), ParkingError> {
+        self.write_snapshot()?;
+
+        match self.journal.as_mut() {
+            Some(journal) => journal.compact(),
+            None => Ok(()),
+        }
+    }
+
+    // Written next to the journal and renamed into place so a crash never leaves half a snapshot behind
+    fn write_snapshot(&self) -> Result<(), ParkingError> {
+        let Some(journal) = self.journal.as_ref() else { return Ok(()) };
+
+        let tmp = journal.path.join(format!("{}.tmp", SNAPSHOT_FILE));
+        let mut file = File::create(&tmp)?;
+        file.write_all(self.snapshot().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, journal.path.join(SNAPSHOT_FILE))?;
+
+        Ok(())
+    }
+
+    // Every spot on every level as JSON, stamped with the last journal entry it includes
+    fn snapshot(&self) -> String {
+        let seq = self.journal.as_ref().map_or(0, |journal| journal.seq);
+        let levels: Vec<String> = self
+            .levels
+            .iter()
+            .map(|floor| {
+                let spots: Vec<String> = floor
+                    .lot
+                    .iter()
+                    .map(|spots| {
+                        let occupied: Vec<&str> = spots.occupied.iter().map(|&taken| if taken { "true" } else { "false" }).collect();
+                        format!(
+                            "{{\"size\":\"{}\",\"capacity\":{},\"taken\":{},\"occupied\":[{}]}}",
+                            spots.car_type,
+                            spots.capacity,
+                            spots.taken,
+                            occupied.join(",")
+                        )
+                    })
+                    .collect();
+                format!("[{}]", spots.join(","))
+            })
+            .collect();
+
+        let parked: Vec<String> = self.parked.values().map(ticket_json).collect();
+
+        format!("{{\"seq\":{},\"levels\":[{}],\"parked\":[{}]}}\n", seq, levels.join(","), parked.join(","))
+    }
+
+    // Latest snapshot plus every journal entry written after it... policy, tariff and clock go back to their defaults
+    fn recover(dir: &Path, snapshot_every: u64) -> Result<Self, ParkingError> {
+        let snapshot = Json::parse(&fs::read_to_string(dir.join(SNAPSHOT_FILE))?)?;
+        let mut seq = snapshot.get("seq")?.as_u64()?;
+
+        let mut levels = Vec::new();
+        for floor in snapshot.get("levels")?.as_array()? {
+            let mut lot = Vec::new();
+            for (spots, car_type) in floor.as_array()?.iter().zip(CarSize::ALL) {
+                let capacity = spots.get("capacity")?.as_u64()? as i32;
+                let mut restored = ParkingSpots::new(car_type, capacity)?;
+
+                for (number, taken) in spots.get("occupied")?.as_array()?.iter().enumerate() {
+                    if taken.as_bool()? && !restored.add_back(number) {
+                        return Err(ParkingError::CorruptState(format!("spot {} of {} doesn't exist", number, car_type)));
+                    }
+                }
+                if restored.taken as u64 != spots.get("taken")?.as_u64()? {
+                    return Err(ParkingError::CorruptState(format!("{} spot count doesn't add up", car_type)));
+                }
+
+                lot.push(restored);
+            }
+            if lot.len() != 3 {
+                return Err(ParkingError::CorruptState(String::from("a level is missing spot sizes")));
+            }
+            levels.push(Level { lot });
+        }
+
+        let mut parked = BTreeMap::new();
+        for ticket in snapshot.get("parked")?.as_array()? {
+            let ticket = parse_ticket(ticket)?;
+            parked.insert(ticket.spot, ticket);
+        }
+
+        let mut recovered = Self {
+            levels,
+            policy: Box::new(Strict),
+            events: Vec::new(),
+            tariff: TariffSchedule::free(),
+            clock: Box::new(SystemClock),
+            journal: None,
+            parked,
+        };
+        recovered.set_verbose(false);
+
+        // A line without its newline was cut off mid-write by the crash, so it never happened
+        let journal = fs::read_to_string(dir.join(JOURNAL_FILE))?;
+        let keep = journal.rfind('\n').map_or(0, |end| end + 1);
+        let complete = &journal[..keep];
+
+        for line in complete.lines() {
+            let (entry_seq, entry) = JournalEntry::parse(line)?;
+            if entry_seq <= seq {
+                continue;
+            }
+
+            let spot = entry.ticket().spot;
+            let spots = recovered
+                .levels
+                .get_mut(spot.level)
+                .map(|floor| floor.spots_mut(spot.car_type))
+                .ok_or(ParkingError::UnknownSpot(spot))?;
+
+            let applied = match entry {
+                JournalEntry::Park(_) => spots.add_back(spot.number),
+                JournalEntry::Leave(_) => spots.remove_car(spot.number),
+            };
+            if !applied {
+                return Err(ParkingError::CorruptState(format!("entry {} doesn't fit the lot", entry_seq)));
+            }
+
+            match entry {
+                JournalEntry::Park(ticket) => recovered.parked.insert(spot, ticket),
+                JournalEntry::Leave(_) => recovered.parked.remove(&spot),
+            };
+            seq = entry_seq;
+        }
+
+        // Cut off just the torn tail so the next append starts on a fresh line. set_len only ever shortens the file,
+        // rewriting it would truncate first and a crash right then would lose entries that aren't anywhere else
+        let file = OpenOptions::new().write(true).open(dir.join(JOURNAL_FILE))?;
+        file.set_len(keep as u64)?;
+        file.sync_all()?;
+
+        recovered.set_verbose(true);
+        recovered.journal = Some(Journal::open(dir, snapshot_every, seq)?);
+        Ok(recovered)
+    }
+}
+////////////////////////////
+///// Multi-Gate Entry /////
+////////////////////////////
+
+// ParkingSpots that several gates can fill at once through a shared reference, the size lives in the level's array slot
+struct ConcurrentParkingSpots {
+    capacity: i32,
+    taken: AtomicI32,
+    occupied: Vec<AtomicBool>,
+}
+
+impl ConcurrentParkingSpots {
+    fn new(capacity: i32) -> Result<Self, ParkingError> {
+        if capacity < 0 {
+            return Err(ParkingError::NegativeCapacity(capacity));
+        }
+
+        Ok(Self {
+            capacity,
+            taken: AtomicI32::new(0),
+            occupied: (0..capacity).map(|_| AtomicBool::new(false)).collect(),
+        })
+    }
+
+    // Counts the car in only while there's room, two gates racing for the last spot can't both get it
+    fn reserve(&self) -> bool {
+        self.taken.fetch_update(Relaxed, Relaxed, |taken| (taken < self.capacity).then_some(taken + 1)).is_ok()
+    }
+
+    // A successful reserve() guarantees a free flag exists, so keep sweeping until one is claimed
+    fn add_car(&self) -> Option<usize> {
+        if !self.reserve() {
+            return None;
+        }
+
+        loop {
+            for (number, spot) in self.occupied.iter().enumerate() {
+                if spot.compare_exchange(false, true, Acquire, Relaxed).is_ok() {
+                    return Some(number);
+                }
+            }
+        }
+    }
+
+    // Free the spot before giving the capacity back so a new reservation always finds it
+    fn remove_car(&self, number: usize) -> bool {
+        match self.occupied.get(number) {
+            Some(spot) if spot.swap(false, Release) => {
+                self.taken.fetch_sub(1, Relaxed);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn taken(&self) -> i32 {
+        self.taken.load(Relaxed)
+    }
+}
+
+// Shareable between gate threads (&self everywhere), places cars with the strict policy: exact size, lowest level first
+struct ConcurrentParkingSystem {
+    levels: Vec<[ConcurrentParkingSpots; 3]>,
+    clock: Box<dyn Clock + Send + Sync>,
+}
+
+impl ConcurrentParkingSystem {
+    fn try_with_levels(levels: &[(i32, i32, i32)]) -> Result<Self, ParkingError> {
+        let levels = levels
+            .iter()
+            .map(|&(big, medium, small)| {
+                Ok([
+                    ConcurrentParkingSpots::new(big)?,
+                    ConcurrentParkingSpots::new(medium)?,
+                    ConcurrentParkingSpots::new(small)?,
+                ])
+            })
+            .collect::<Result<Vec<_>, ParkingError>>()?;
+
+        Ok(Self { levels, clock: Box::new(SystemClock) })
+    }
+
+    // Shared by every gate thread, so a ManualClock (Rc inside) won't do here
+    fn set_clock(&mut self, clock: Box<dyn Clock + Send + Sync>) {
+        self.clock = clock;
+    }
+
+    fn add_car(&self, car_type: CarSize) -> Option<Ticket> {
+        self.levels.iter().enumerate().find_map(|(level, floor)| {
+            let number = floor[car_type.index()].add_car()?;
+
+            Some(Ticket {
+                spot: SpotId { level, car_type, number },
+                car_type,
+                arrival: self.clock.now(),
+            })
+        })
+    }
+
+    fn remove_car(&self, ticket: Ticket) -> Result<(), ParkingError> {
+        let spot = ticket.spot;
+        let spots = self
+            .levels
+            .get(spot.level)
+            .map(|floor| &floor[spot.car_type.index()])
+            .ok_or(ParkingError::UnknownSpot(spot))?;
+
+        if spot.number >= spots.occupied.len() {
+            return Err(ParkingError::UnknownSpot(spot));
+        }
+
+        if spots.remove_car(spot.number) {
+            Ok(())
+        } else {
+            Err(ParkingError::SpotAlreadyEmpty(spot))
+        }
+    }
+
+    fn taken(&self, car_type: CarSize) -> i32 {
+        self.levels.iter().map(|floor| floor[car_type.index()].taken()).sum()
+    }
+
+    fn capacity(&self, car_type: CarSize) -> i32 {
+        self.levels.iter().map(|floor| floor[car_type.index()].capacity).sum()
+    }
+}
+
+////////////////////////////
+//////// Simulation ////////
+////////////////////////////
+
+// How long a car stays once it's parked, in seconds
+#[derive(Debug, Clone, Copy)]
+enum DwellTime {
+    Fixed(u64),
+    Uniform { min: u64, max: u64 },
+    Exponential { mean: f64 },
+}
+
+impl DwellTime {
+    fn sample(&self, rng: &mut StdRng) -> u64 {
+        match *self {
+            DwellTime::Fixed(secs) => secs,
+            DwellTime::Uniform { min, max } => rng.gen_range(min..=max),
+            DwellTime::Exponential { mean } => exponential(rng, mean) as u64,
+        }
+    }
+}
+
+// Inverse transform sampling: -mean * ln(U), with U in (0, 1]
+fn exponential(rng: &mut StdRng, mean: f64) -> f64 {
+    let u: f64 = rng.gen();
+    -mean * (1.0 - u).ln()
+}
+
+#[derive(Debug, Clone)]
+struct SimulationConfig {
+    seed: u64,
+    days: u64,
+    arrivals_per_hour: f64, // Poisson arrival rate
+    size_mix: [f64; 3],     // relative weights indexed by CarSize::index()
+    dwell: DwellTime,
+    patience: u64,      // seconds a turned away car waits at the gate before giving up
+    sample_every: u64,  // seconds between occupancy samples
+}
+
+impl SimulationConfig {
+    // Anything here would either divide by zero, never let virtual time move on or panic part way through a run
+    fn validate(&self) -> Result<(), ParkingError> {
+        let bad = |why: &str| Err(ParkingError::BadSimulation(why.to_string()));
+
+        if !(self.arrivals_per_hour.is_finite() && self.arrivals_per_hour > 0.0) {
+            return bad("arrivals_per_hour has to be above 0");
+        }
+        if self.size_mix.iter().any(|&weight| !(weight.is_finite() && weight >= 0.0)) || self.size_mix.iter().sum::<f64>() <= 0.0 {
+            return bad("size_mix needs non-negative weights and at least one above 0");
+        }
+        if self.sample_every == 0 {
+            return bad("sample_every has to be at least a second");
+        }
+        if self.days.checked_mul(DAY).is_none() {
+            return bad("too many days");
+        }
+
+        match self.dwell {
+            DwellTime::Uniform { min, max } if min > max => bad("dwell min is longer than max"),
+            DwellTime::Exponential { mean } if !(mean.is_finite() && mean >= 0.0) => bad("dwell mean has to be 0 or more"),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SimEvent {
+    Arrival,
+    Departure(Ticket),
+    GiveUp(u64),
+    Sample,
+}
+
+// Ordered by time, then by the order it was scheduled in so ties replay the same way every run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Scheduled {
+    at: u64,
+    seq: u64,
+    event: SimEvent,
+}
+
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.at, self.seq).cmp(&(other.at, other.seq))
+    }
+}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+struct WaitingCar {
+    id: u64,
+    car_type: CarSize,
+    since: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+struct SimulationReport {
+    arrivals: u64,
+    parked: u64,
+    rejected: u64,
+    total_wait: u64,
+    occupancy: Vec<(u64, f64)>, // (virtual seconds, utilisation)
+}
+
+impl SimulationReport {
+    fn rejection_rate(&self) -> f64 {
+        if self.arrivals == 0 {
+            0.0
+        } else {
+            self.rejected as f64 / self.arrivals as f64
+        }
+    }
+
+    // Seconds from reaching the gate to getting a spot, averaged over every car that parked
+    fn average_wait(&self) -> f64 {
+        if self.parked == 0 {
+            0.0
+        } else {
+            self.total_wait as f64 / self.parked as f64
+        }
+    }
+
+    fn average_occupancy(&self) -> f64 {
+        if self.occupancy.is_empty() {
+            0.0
+        } else {
+            self.occupancy.iter().map(|&(_, used)| used).sum::<f64>() / self.occupancy.len() as f64
+        }
+    }
+}
+
+impl fmt::Display for SimulationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} arrivals, {} parked, {:.1}% rejected, {:.1}s average wait, {:.1}% average occupancy",
+            self.arrivals,
+            self.parked,
+            self.rejection_rate() * 100.0,
+            self.average_wait(),
+            self.average_occupancy() * 100.0,
+        )
+    }
+}
+
+// Drives a ParkingSystem on virtual time: nothing sleeps, the clock jumps from one event to the next
+// Arrivals and dwell times draw from separate streams so every policy sees the same cars for a given seed
+struct Simulation {
+    config: SimulationConfig,
+    arrivals: StdRng,
+    dwells: StdRng,
+    clock: ManualClock,
+    lot: ParkingSystem,
+    queue: BinaryHeap<Reverse<Scheduled>>,
+    waiting: VecDeque<WaitingCar>,
+    seq: u64,
+    report: SimulationReport,
+}
+
+impl Simulation {
+    fn try_new(config: SimulationConfig, mut lot: ParkingSystem) -> Result<Self, ParkingError> {
+        config.validate()?;
+
+        let clock = ManualClock::new(0);
+        lot.set_clock(Box::new(clock.clone()));
+        lot.set_verbose(false);
+
+        Ok(Self {
+            arrivals: StdRng::seed_from_u64(config.seed),
+            dwells: StdRng::seed_from_u64(config.seed.wrapping_add(1)),
+            config,
+            clock,
+            lot,
+            queue: BinaryHeap::new(),
+            waiting: VecDeque::new(),
+            seq: 0,
+            report: SimulationReport::default(),
+        })
+    }
+
+    fn schedule(&mut self, at: u64, event: SimEvent) {
+        self.seq += 1;
+        self.queue.push(Reverse(Scheduled { at, seq: self.seq, event }));
+    }
+
+    fn next_arrival(&mut self) -> u64 {
+        let mean = HOUR as f64 / self.config.arrivals_per_hour;
+        self.clock.now().saturating_add(exponential(&mut self.arrivals, mean).ceil() as u64)
+    }
+
+    fn pick_size(&mut self) -> CarSize {
+        let total: f64 = self.config.size_mix.iter().sum();
+        let mut roll = self.arrivals.gen::<f64>() * total;
+
+        for size in CarSize::ALL {
+            roll -= self.config.size_mix[size.index()];
+            if roll < 0.0 {
+                return size;
+            }
+        }
+
+        CarSize::Small
+    }
+
+    fn park(&mut self, car_type: CarSize, since: u64) -> bool {
+        match self.lot.add_car(car_type) {
+            Some(ticket) => {
+                let now = self.clock.now();
+                self.report.parked += 1;
+                self.report.total_wait += now - since;
+
+                let dwell = self.config.dwell.sample(&mut self.dwells);
+                self.schedule(now + dwell, SimEvent::Departure(ticket));
+                true
+            }
+            None => false,
+        }
+    }
+
+    // A spot just opened up... first come, first served among the cars that fit
+    fn admit_waiting(&mut self) {
+        let mut i = 0;
+        while i < self.waiting.len() {
+            let car = &self.waiting[i];
+            let (car_type, since) = (car.car_type, car.since);
+
+            if self.park(car_type, since) {
+                self.waiting.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    fn run(mut self) -> SimulationReport {
+        let end = self.config.days * DAY;
+
+        let first = self.next_arrival();
+        self.schedule(first, SimEvent::Arrival);
+        self.schedule(0, SimEvent::Sample);
+
+        while let Some(Reverse(next)) = self.queue.pop() {
+            if next.at > end {
+                break;
+            }
+            self.clock.set(next.at);
+
+            match next.event {
+                SimEvent::Arrival => {
+                    self.report.arrivals += 1;
+                    let car_type = self.pick_size();
+
+                    if !self.park(car_type, next.at) {
+                        let id = self.seq;
+                        self.waiting.push_back(WaitingCar { id, car_type, since: next.at });
+                        self.schedule(next.at + self.config.patience, SimEvent::GiveUp(id));
+                    }
+
+                    let at = self.next_arrival();
+                    self.schedule(at, SimEvent::Arrival);
+                }
+                SimEvent::Departure(ticket) => {
+                    if self.lot.leave(ticket).is_ok() {
+                        self.admit_waiting();
+                    }
+                }
+                SimEvent::GiveUp(id) => {
+                    if let Some(i) = self.waiting.iter().position(|car| car.id == id) {
+                        self.waiting.remove(i);
+                        self.report.rejected += 1;
+                    }
+                }
+                SimEvent::Sample => {
+                    self.report.occupancy.push((next.at, self.lot.utilisation()));
+                    self.schedule(next.at + self.config.sample_every, SimEvent::Sample);
+                }
+            }
+        }
+
+        self.report
+    }
+}
+
+////////////////////////////
+////// Operator Shell //////
+////////////////////////////
+
+const SHELL_HELP: &str = "commands:
+  park <size>                      park a big, medium or small car
+  leave <spot>                     let the car in a spot (e.g. L1-S-01) out and print its receipt
+  status                           free/total spots per level
+  report                           cars in, cars turned away, occupancy and takings
+  resize <size> <capacity> [level] change how many spots of a size a level has (level 1 by default)
+  save <dir>                       snapshot the lot to dir and keep journaling there
+  load <dir>                       recover the lot from dir
+  help                             show this again
+  quit                             leave the shell";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Command {
+    Park(CarSize),
+    Leave(SpotId),
+    Status,
+    Report,
+    Resize { car_type: CarSize, capacity: i32, level: usize },
+    Save(PathBuf),
+    Load(PathBuf),
+    Help,
+    Quit,
+}
+
+fn parse_command(line: &str) -> Result<Command, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+
+    let command = match words.as_slice() {
+        ["park", size] => Command::Park(size.parse().map_err(|e: ParkingError| e.to_string())?),
+        ["leave", spot] => Command::Leave(spot.parse().map_err(|e: ParkingError| e.to_string())?),
+        ["status"] => Command::Status,
+        ["report"] => Command::Report,
+        ["resize", size, capacity, rest @ ..] if rest.len() <= 1 => {
+            let level = match rest.first() {
+                Some(level) => match level.parse::<usize>() {
+                    Ok(level) if level > 0 => level - 1,
+                    _ => return Err(format!("{} isn't a level, levels start at 1", level)),
+                },
+                None => 0,
+            };
+
+            Command::Resize {
+                car_type: size.parse().map_err(|e: ParkingError| e.to_string())?,
+                capacity: capacity.parse().map_err(|_| format!("{} isn't a number", capacity))?,
+                level,
+            }
+        }
+        ["save", dir] => Command::Save(PathBuf::from(dir)),
+        ["load", dir] => Command::Load(PathBuf::from(dir)),
+        ["help"] | ["?"] => Command::Help,
+        ["quit"] | ["exit"] => Command::Quit,
+        [] => return Err(String::from("type a command, or help")),
+        [name, ..] => {
+            let known = ["park", "leave", "status", "report", "resize", "save", "load", "help", "quit"];
+            return Err(if known.contains(name) {
+                format!("wrong arguments for {}, see help", name)
+            } else {
+                format!("unknown command {}, see help", name)
+            });
+        }
+    };
+
+    Ok(command)
+}
+
+fn read_next_line() -> Option<String> {
+    let mut line = String::new();
+
+    match io::stdin().read_line(&mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line.trim().to_string()),
+    }
+}
+
+fn shell_tariff() -> TariffSchedule {
+    TariffSchedule {
+        day_rates: [Cents(400), Cents(300), Cents(200)],
+        night_rates: [Cents(200), Cents(150), Cents(100)],
+        night_start: 22,
+        night_end: 6,
+        grace_period: 15 * 60,
+        daily_max: [Cents(3000), Cents(2200), Cents(1500)],
+    }
+}
+
+// Line-oriented, tab-separated output so it pastes straight into a spreadsheet
+fn run_shell() {
+    let mut lot = ParkingSystem::with_levels(&[(5, 2, 1), (2, 2, 2)]);
+    lot.set_policy(Box::new(BestFit));
+    lot.set_tariff(shell_tariff());
+    lot.set_verbose(false);
+
+    let mut takings = Cents(0);
+    let mut commands = 0;
+
+    println!("Parking lot shell, type help for commands");
+
+    'shell: loop {
+        print!("lot> ");
+        let _ = io::stdout().flush();
+
+        let line = match read_next_line() {
+            Some(line) => line,
+            None => break 'shell,
+        };
+
+        let command = match parse_command(&line) {
+            Ok(command) => command,
+            Err(e) => {
+                println!("error: {}", e);
+                continue;
+            }
+        };
+
+        match command {
+            Command::Park(car_type) => match lot.add_car(car_type) {
+                Some(ticket) => println!("parked\t{}\t{}", ticket.spot, ticket.car_type),
+                None => println!("full\tno room for a {} car", car_type),
+            },
+            Command::Leave(spot) => match lot.ticket_at(spot) {
+                Some(ticket) => match lot.leave(ticket) {
+                    Ok(receipt) => {
+                        takings = Cents(takings.0 + receipt.total.0);
+                        println!("{}", receipt);
+                    }
+                    Err(e) => println!("error: {}", e),
+                },
+                None => println!("error: nobody is parked in {}", spot),
+            },
+            Command::Status => {
+                println!("level\tbig\tmedium\tsmall");
+                for (level, ((big, medium, small), floor)) in lot.free_spots_per_level().into_iter().zip(&lot.levels).enumerate() {
+                    let columns: Vec<String> = [big, medium, small]
+                        .iter()
+                        .zip(CarSize::ALL)
+                        .map(|(free, size)| format!("{}/{}", free, floor.spots(size).capacity))
+                        .collect();
+                    println!("L{}\t{}", level + 1, columns.join("\t"));
+                }
+            }
+            Command::Report => {
+                let count = |wanted: fn(&Event) -> bool| lot.events().iter().filter(|event| wanted(event)).count();
+                println!("parked\t{}", count(|event| matches!(event, Event::Parked { .. })));
+                println!("turned away\t{}", count(|event| matches!(event, Event::Rejected { .. })));
+                println!("left\t{}", count(|event| matches!(event, Event::Left { .. })));
+                println!("occupancy\t{:.1}%", lot.utilisation() * 100.0);
+                println!("takings\t{}", takings);
+            }
+            Command::Resize { car_type, capacity, level } => match lot.resize(level, car_type, capacity) {
+                Ok(()) => println!("resized\tL{}\t{}\t{}\t{} free", level + 1, car_type, capacity, lot.free_spots(level, car_type).unwrap_or(0)),
+                Err(e) => println!("error: {}", e),
+            },
+            Command::Save(dir) => match lot.enable_persistence(&dir, 50) {
+                Ok(()) => println!("saved\t{}", dir.display()),
+                Err(e) => println!("error: {}", e),
+            },
+            Command::Load(dir) => match ParkingSystem::recover(&dir, 50) {
+                Ok(mut recovered) => {
+                    recovered.set_policy(Box::new(BestFit));
+                    recovered.set_tariff(shell_tariff());
+                    recovered.set_verbose(false);
+                    lot = recovered;
+                    println!("loaded\t{}", dir.display());
+                }
+                Err(e) => println!("error: {}", e),
+            },
+            Command::Help => println!("{}", SHELL_HELP),
+            Command::Quit => break 'shell,
+        }
+
+        commands += 1;
+    }
+
+    println!("Ran {} commands, bye!", commands);
+}
+
+////////////////////////////
+/////// Client Code ////////
+////////////////////////////
+
+fn main() {
+    // cargo run -- shell for the operator shell, otherwise the simulations below
+    if std::env::args().any(|arg| arg == "shell") {
+        run_shell();
+        return;
+    }
+
+    // Pun intended... two floors, a busy weekday rate and a mostly small-car crowd
+    let config = SimulationConfig {
+        seed: 1603,
+        days: 2000,
+        arrivals_per_hour: 6.0,
+        size_mix: [1.0, 2.0, 3.0],
+        dwell: DwellTime::Exponential { mean: 2.0 * HOUR as f64 },
+        patience: 10 * 60,
+        sample_every: HOUR,
+    };
+
+    // Same seed, same arrivals... how does each policy cope?
+    let policies: Vec<Box<dyn AllocationPolicy>> = vec![Box::new(Strict), Box::new(UpgradeToLarger), Box::new(BestFit)];
+
+    for policy in policies {
+        let mut lot = match ParkingSystem::try_with_levels(&[(5, 2, 1), (2, 2, 2)]) {
+            Ok(lot) => lot,
+            Err(e) => {
+                println!("Couldn't build the lot: {}", e);
+                return;
+            }
+        };
+        let name = policy.name().to_string();
+        lot.set_policy(policy);
+        lot.set_tariff(TariffSchedule {
+            day_rates: [Cents(400), Cents(300), Cents(200)],
+            night_rates: [Cents(200), Cents(150), Cents(100)],
+            night_start: 22,
+            night_end: 6,
+            grace_period: 15 * 60,
+            daily_max: [Cents(3000), Cents(2200), Cents(1500)],
+        });
+
+        let started = time::Instant::now();
+        let report = match Simulation::try_new(config.clone(), lot) {
+            Ok(simulation) => simulation.run(),
+            Err(e) => {
+                println!("{}", e);
+                return;
+            }
+        };
+
+        println!("{} ({} days in {:.2?}): {}", name, config.days, started.elapsed(), report);
+    }
+
+    // Same arrivals under best fit, only how long people stay changes... two hours on average every time
+    let dwells = [DwellTime::Fixed(2 * HOUR), DwellTime::Uniform { min: HOUR, max: 3 * HOUR }, config.dwell];
+    for dwell in dwells {
+        let Ok(mut lot) = ParkingSystem::try_with_levels(&[(5, 2, 1), (2, 2, 2)]) else { continue };
+        lot.set_policy(Box::new(BestFit));
+
+        match Simulation::try_new(SimulationConfig { days: 200, dwell, ..config.clone() }, lot) {
+            Ok(simulation) => println!("{:?} (200 days): {}", dwell, simulation.run()),
+            Err(e) => println!("{}", e),
+        }
+    }
+
+    // Four gates letting small cars in at the same time during the 8am rush, then each lets its first car back out...
+    // the lot never overfills
+    if let Ok(mut shared_lot) = ConcurrentParkingSystem::try_with_levels(&[(5, 2, 3), (2, 2, 3)]) {
+        shared_lot.set_clock(Box::new(FixedClock(8 * HOUR)));
+        thread::scope(|s| {
+            for gate in 1..=4 {
+                let shared_lot = &shared_lot;
+                s.spawn(move || {
+                    let tickets: Vec<Ticket> = (0..3).filter_map(|_| shared_lot.add_car(CarSize::Small)).collect();
+                    let left = tickets.first().is_some_and(|&ticket| shared_lot.remove_car(ticket).is_ok());
+                    println!("Gate {} let {} small cars in, first one back out: {}", gate, tickets.len(), left);
+                });
+            }
+        });
+        println!(
+            "Small spots taken: {}/{}",
+            shared_lot.taken(CarSize::Small),
+            shared_lot.capacity(CarSize::Small)
+        );
+    }
+
+    // Crash and come back: snapshot plus journal tail puts every car back where it was
+    let state_dir = std::env::temp_dir().join("ch5_parking_state");
+    let mut durable_lot = ParkingSystem::new(2, 2, 2);
+    durable_lot.set_verbose(false);
+    match durable_lot.enable_persistence(&state_dir, 3) {
+        Ok(()) => {
+            for size in [CarSize::Small, CarSize::Big, CarSize::Small, CarSize::Medium] {
+                durable_lot.add_car(size);
+            }
+            let before = durable_lot.snapshot();
+            drop(durable_lot);
+
+            match ParkingSystem::recover(&state_dir, 3) {
+                Ok(recovered) => println!("Recovered identical state: {}", recovered.snapshot() == before),
+                Err(e) => println!("{}", e),
+            }
+        }
+        Err(e) => println!("{}", e),
+    }
+
+    // Bad input is reported instead of blowing up
+    for input in ["medium", "huge"] {
+        match input.parse::<CarSize>() {
+            Ok(car_type) => println!("{} parsed fine", car_type),
+            Err(e) => println!("{}", e),
+        }
+    }
+    if let Err(e) = ParkingSystem::try_new(1, -2, 3) {
+        println!("{}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tariff() -> TariffSchedule {
+        TariffSchedule {
+            day_rates: [Cents(400), Cents(300), Cents(200)],
+            night_rates: [Cents(200), Cents(150), Cents(100)],
+            night_start: 22,
+            night_end: 6,
+            grace_period: 15 * 60,
+            daily_max: [Cents(3000), Cents(2200), Cents(1500)],
+        }
+    }
+
+    fn paid_lot(clock: &ManualClock) -> ParkingSystem {
+        let mut lot = ParkingSystem::new(2, 2, 2);
+        lot.set_clock(Box::new(clock.clone()));
+        lot.set_tariff(tariff());
+        lot
+    }
+
+    #[test]
+    fn car_sizes_read_back_what_they_print() {
+        for size in CarSize::ALL {
+            assert_eq!(size.to_string().parse::<CarSize>(), Ok(size));
+            assert_eq!(CarSize::try_from(size.index() as i32 + 1), Ok(size));
+        }
+
+        // The old Leetcode codes and any case still work
+        assert_eq!(" 2 ".parse::<CarSize>(), Ok(CarSize::Medium));
+        assert_eq!("SMALL".parse::<CarSize>(), Ok(CarSize::Small));
+        assert_eq!("huge".parse::<CarSize>(), Err(ParkingError::UnsupportedSize(String::from("huge"))));
+        assert_eq!(CarSize::try_from(4), Err(ParkingError::UnsupportedSize(String::from("4"))));
+        assert_eq!(CarSize::try_from(0), Err(ParkingError::UnsupportedSize(String::from("0"))));
+    }
+
+    #[test]
+    fn negative_capacities_are_refused() {
+        assert!(matches!(ParkingSystem::try_new(1, -2, 0), Err(ParkingError::NegativeCapacity(-2))));
+        assert!(matches!(ParkingSystem::try_with_levels(&[(1, 1, 1), (0, 0, -1)]), Err(ParkingError::NegativeCapacity(-1))));
+        assert!(ParkingSystem::try_new(0, 0, 0).is_ok());
+    }
+
+    #[test]
+    fn spots_read_back_what_they_print() {
+        let spot = SpotId { level: 1, car_type: CarSize::Medium, number: 6 };
+        assert_eq!(spot.to_string(), "L2-M-07");
+        assert_eq!("L2-M-07".parse::<SpotId>(), Ok(spot));
+        assert_eq!(" l2-m-7 ".parse::<SpotId>(), Ok(spot));
+
+        for bad in ["L2-X-07", "L0-M-07", "L2-M-00", "2-M-07", "L2-M", "L2-M-07-1", ""] {
+            assert_eq!(bad.parse::<SpotId>(), Err(ParkingError::InvalidSpot(String::from(bad))), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn shell_commands_parse() {
+        let spot = SpotId { level: 0, car_type: CarSize::Small, number: 0 };
+
+        assert_eq!(parse_command("park small"), Ok(Command::Park(CarSize::Small)));
+        assert_eq!(parse_command("  leave   L1-S-01 "), Ok(Command::Leave(spot)));
+        assert_eq!(parse_command("status"), Ok(Command::Status));
+        assert_eq!(parse_command("report"), Ok(Command::Report));
+        assert_eq!(parse_command("resize big 3"), Ok(Command::Resize { car_type: CarSize::Big, capacity: 3, level: 0 }));
+        assert_eq!(parse_command("resize medium 0 2"), Ok(Command::Resize { car_type: CarSize::Medium, capacity: 0, level: 1 }));
+        assert_eq!(parse_command("save lot"), Ok(Command::Save(PathBuf::from("lot"))));
+        assert_eq!(parse_command("load lot"), Ok(Command::Load(PathBuf::from("lot"))));
+        assert_eq!(parse_command("?"), Ok(Command::Help));
+        assert_eq!(parse_command("exit"), Ok(Command::Quit));
+    }
+
+    #[test]
+    fn bad_shell_commands_say_what_was_wrong() {
+        assert_eq!(parse_command(""), Err(String::from("type a command, or help")));
+        assert_eq!(parse_command("park huge"), Err(String::from("huge isn't supported")));
+        assert_eq!(parse_command("leave L1-Q-01"), Err(String::from("L1-Q-01 isn't a spot, try something like L1-S-01")));
+        assert_eq!(parse_command("resize big lots"), Err(String::from("lots isn't a number")));
+        assert_eq!(parse_command("resize big 3 0"), Err(String::from("0 isn't a level, levels start at 1")));
+        assert_eq!(parse_command("resize big 3 1 2"), Err(String::from("wrong arguments for resize, see help")));
+        assert_eq!(parse_command("park"), Err(String::from("wrong arguments for park, see help")));
+        assert_eq!(parse_command("fly away"), Err(String::from("unknown command fly, see help")));
+    }
+
+    #[test]
+    fn levels_fill_and_free_one_class_at_a_time() {
+        let mut lot = ParkingSystem::with_levels(&[(1, 2, 0), (1, 0, 1)]);
+        lot.set_verbose(false);
+
+        // Strict sends each car to the lowest level with its own class free
+        let big: Vec<Ticket> = (0..3).filter_map(|_| lot.add_car(CarSize::Big)).collect();
+        assert_eq!(big.iter().map(|ticket| ticket.spot.level).collect::<Vec<_>>(), vec![0, 1]);
+        let small = lot.add_car(CarSize::Small).unwrap();
+        assert_eq!(small.spot.level, 1);
+        lot.add_car(CarSize::Medium).unwrap();
+
+        assert_eq!(lot.free_spots_per_level(), vec![(0, 1, 0), (0, 0, 0)]);
+        assert_eq!(lot.free_spots(1, CarSize::Small), Some(0));
+        assert_eq!(lot.free_spots(2, CarSize::Small), None);
+
+        // Leaving on the top floor only frees that floor
+        lot.remove_car(big[1]).unwrap();
+        lot.remove_car(small).unwrap();
+        assert_eq!(lot.free_spots_per_level(), vec![(0, 1, 0), (1, 0, 1)]);
+        assert_eq!(lot.remove_car(small), Err(ParkingError::SpotAlreadyEmpty(small.spot)));
+
+        let ghost = Ticket { spot: SpotId { level: 5, car_type: CarSize::Big, number: 0 }, ..small };
+        assert_eq!(lot.remove_car(ghost), Err(ParkingError::UnknownSpot(ghost.spot)));
+    }
+
+    #[test]
+    fn policies_disagree_on_where_a_small_car_goes() {
+        // Ground floor has a big and a medium spot, the floor above only a small one
+        let levels = vec![Level::new(1, 1, 0).unwrap(), Level::new(0, 0, 1).unwrap()];
+
+        assert_eq!(Strict.choose(CarSize::Small, &levels), Some((1, CarSize::Small)));
+        assert_eq!(UpgradeToLarger.choose(CarSize::Small, &levels), Some((0, CarSize::Medium)));
+        assert_eq!(BestFit.choose(CarSize::Small, &levels), Some((1, CarSize::Small)));
+
+        // Small spots all gone: only Strict turns the car away
+        let mut levels = levels;
+        levels[1].spots_mut(CarSize::Small).verbose = false;
+        levels[1].spots_mut(CarSize::Small).add_car().unwrap();
+        assert_eq!(Strict.choose(CarSize::Small, &levels), None);
+        assert_eq!(UpgradeToLarger.choose(CarSize::Small, &levels), Some((0, CarSize::Medium)));
+        assert_eq!(BestFit.choose(CarSize::Small, &levels), Some((0, CarSize::Medium)));
+
+        // Nobody squeezes a big car into a smaller spot
+        levels[0].spots_mut(CarSize::Big).verbose = false;
+        levels[0].spots_mut(CarSize::Big).add_car().unwrap();
+        assert_eq!(UpgradeToLarger.choose(CarSize::Big, &levels), None);
+        assert_eq!(BestFit.choose(CarSize::Big, &levels), None);
+    }
+
+    #[test]
+    fn the_event_log_records_every_decision() {
+        let mut lot = ParkingSystem::new(1, 0, 0);
+        lot.set_verbose(false);
+        lot.set_clock(Box::new(ManualClock::new(HOUR)));
+
+        let parked = lot.add_car(CarSize::Big).unwrap();
+        assert_eq!(lot.add_car(CarSize::Small), None);
+        lot.set_policy(Box::new(UpgradeToLarger));
+        assert_eq!(lot.add_car(CarSize::Small), None);
+        lot.remove_car(parked).unwrap();
+        let upgraded = lot.add_car(CarSize::Small).unwrap();
+        assert_eq!(upgraded.spot.car_type, CarSize::Big);
+
+        assert_eq!(
+            lot.events(),
+            &[
+                Event::Parked { ticket: parked, policy: String::from("strict") },
+                Event::Rejected { car_type: CarSize::Small, policy: String::from("strict") },
+                Event::Rejected { car_type: CarSize::Small, policy: String::from("upgrade-to-larger") },
+                Event::Left { ticket: parked },
+                Event::Parked { ticket: upgraded, policy: String::from("upgrade-to-larger") },
+            ]
+        );
+    }
+
+    #[test]
+    fn an_old_ticket_cannot_move_the_next_car_out() {
+        let clock = ManualClock::new(10 * HOUR);
+        let mut lot = paid_lot(&clock);
+        lot.set_verbose(false);
+
+        let first = lot.add_car(CarSize::Big).unwrap();
+        lot.leave(first).unwrap();
+        clock.advance(HOUR);
+        let second = lot.add_car(CarSize::Big).unwrap();
+        assert_eq!(second.spot, first.spot);
+
+        // Same spot, different car... the first ticket is no good any more
+        clock.advance(3 * HOUR);
+        assert_eq!(lot.leave(first), Err(ParkingError::WrongTicket(first.spot)));
+        assert_eq!(lot.ticket_at(second.spot), Some(second));
+        assert_eq!(lot.leave(second).unwrap().total, Cents(3 * 400));
+    }
+
+    #[test]
+    fn short_stays_fall_in_the_grace_period() {
+        let clock = ManualClock::new(10 * HOUR);
+        let mut lot = paid_lot(&clock);
+
+        let ticket = lot.add_car(CarSize::Small).unwrap();
+        clock.advance(10 * 60);
+
+        assert_eq!(lot.leave(ticket).unwrap().total, Cents(0));
+    }
+
+    #[test]
+    fn every_started_hour_is_charged_at_day_or_night_rate() {
+        // 21:00 -> 23:30 is one day hour and two started night hours
+        let clock = ManualClock::new(21 * HOUR);
+        let mut lot = paid_lot(&clock);
+
+        let ticket = lot.add_car(CarSize::Big).unwrap();
+        clock.advance(2 * HOUR + 30 * 60);
+
+        let receipt = lot.leave(ticket).unwrap();
+        assert_eq!(receipt.lines.len(), 1);
+        assert_eq!(receipt.total, Cents(400 + 2 * 200));
+    }
+
+    #[test]
+    fn each_calendar_day_is_capped_separately() {
+        let clock = ManualClock::new(6 * HOUR);
+        let mut lot = paid_lot(&clock);
+
+        let ticket = lot.add_car(CarSize::Medium).unwrap();
+        clock.advance(DAY + 2 * HOUR);
+
+        // Day 1 hits the 22.00 cap, day 2 is six night hours and two day hours
+        let receipt = lot.leave(ticket).unwrap();
+        assert_eq!(receipt.lines.len(), 2);
+        assert_eq!(receipt.total, Cents(2200 + 6 * 150 + 2 * 300));
+    }
+
+    #[test]
+    fn the_same_seed_replays_the_same_simulation() {
+        let config = SimulationConfig {
+            seed: 42,
+            days: 30,
+            arrivals_per_hour: 4.0,
+            size_mix: [1.0, 1.0, 1.0],
+            dwell: DwellTime::Uniform { min: 30 * 60, max: 4 * HOUR },
+            patience: 5 * 60,
+            sample_every: HOUR,
+        };
+
+        let first = Simulation::try_new(config.clone(), ParkingSystem::new(2, 2, 2)).unwrap().run();
+        let second = Simulation::try_new(config, ParkingSystem::new(2, 2, 2)).unwrap().run();
+
+        assert_eq!(first, second);
+        assert!(first.parked + first.rejected <= first.arrivals);
+        assert_eq!(first.occupancy.len(), 30 * 24 + 1);
+    }
+
+    #[test]
+    fn fixed_stays_with_room_to_spare_park_everyone() {
+        // Plenty of room, so every car parks and stays exactly two hours
+        let config = SimulationConfig {
+            seed: 7,
+            days: 2,
+            arrivals_per_hour: 3.0,
+            size_mix: [0.0, 0.0, 1.0],
+            dwell: DwellTime::Fixed(2 * HOUR),
+            patience: 0,
+            sample_every: 60,
+        };
+        let report = Simulation::try_new(config, ParkingSystem::new(0, 0, 100)).unwrap().run();
+
+        assert_eq!((report.parked, report.rejected), (report.arrivals, 0));
+        assert!(report.arrivals > 0);
+        assert_eq!(report.occupancy[0], (0, 0.0));
+        assert_eq!(report.average_wait(), 0.0);
+    }
+
+    #[test]
+    fn nonsense_simulations_are_refused() {
+        let good = SimulationConfig {
+            seed: 1,
+            days: 1,
+            arrivals_per_hour: 1.0,
+            size_mix: [1.0, 1.0, 1.0],
+            dwell: DwellTime::Fixed(HOUR),
+            patience: 0,
+            sample_every: HOUR,
+        };
+        let refused = |config: SimulationConfig| {
+            matches!(Simulation::try_new(config, ParkingSystem::new(1, 1, 1)), Err(ParkingError::BadSimulation(_)))
+        };
+
+        assert!(Simulation::try_new(good.clone(), ParkingSystem::new(1, 1, 1)).is_ok());
+        assert!(refused(SimulationConfig { arrivals_per_hour: 0.0, ..good.clone() }));
+        assert!(refused(SimulationConfig { arrivals_per_hour: f64::NAN, ..good.clone() }));
+        assert!(refused(SimulationConfig { size_mix: [0.0, 0.0, 0.0], ..good.clone() }));
+        assert!(refused(SimulationConfig { size_mix: [1.0, -1.0, 1.0], ..good.clone() }));
+        assert!(refused(SimulationConfig { sample_every: 0, ..good.clone() }));
+        assert!(refused(SimulationConfig { days: u64::MAX, ..good.clone() }));
+        assert!(refused(SimulationConfig { dwell: DwellTime::Uniform { min: 2, max: 1 }, ..good.clone() }));
+        assert!(refused(SimulationConfig { dwell: DwellTime::Exponential { mean: -1.0 }, ..good }));
+    }
+
+    // Counted from the spots themselves, not from the taken counter add_car reserves against
+    fn occupied(lot: &ConcurrentParkingSystem, car_type: CarSize) -> i32 {
+        lot.levels
+            .iter()
+            .flat_map(|floor| floor[car_type.index()].occupied.iter())
+            .filter(|spot| spot.load(Relaxed))
+            .count() as i32
+    }
+
+    #[test]
+    fn concurrent_gates_never_exceed_capacity() {
+        let mut lot = ConcurrentParkingSystem::try_with_levels(&[(3, 4, 5), (2, 2, 2)]).unwrap();
+        lot.set_clock(Box::new(FixedClock(7 * HOUR)));
+        let tickets = std::sync::Mutex::new(Vec::new());
+        let inside: [AtomicI32; 3] = [AtomicI32::new(0), AtomicI32::new(0), AtomicI32::new(0)];
+
+        // Twelve gates all trying to squeeze far more cars in than there is room for
+        thread::scope(|s| {
+            for gate in 0..12 {
+                let (lot, tickets, inside) = (&lot, &tickets, &inside);
+                s.spawn(move || {
+                    for i in 0..200 {
+                        let car_type = CarSize::ALL[(gate + i) % 3];
+                        if let Some(ticket) = lot.add_car(car_type) {
+                            // Cars that hold a ticket right now, every one of them on a spot marked taken
+                            let cars = inside[car_type.index()].fetch_add(1, Relaxed) + 1;
+                            assert!(cars <= lot.capacity(car_type));
+                            assert!(lot.levels[ticket.spot.level][car_type.index()].occupied[ticket.spot.number].load(Relaxed));
+                            assert_eq!(ticket.arrival, 7 * HOUR);
+
+                            // Every other car leaves again straight away to keep the spots churning
+                            if i % 2 == 0 {
+                                inside[car_type.index()].fetch_sub(1, Relaxed);
+                                lot.remove_car(ticket).unwrap();
+                            } else {
+                                tickets.lock().unwrap().push(ticket);
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        let tickets = tickets.into_inner().unwrap();
+        for size in CarSize::ALL {
+            let held = tickets.iter().filter(|ticket| ticket.car_type == size).count() as i32;
+            assert_eq!(held, occupied(&lot, size));
+            assert_eq!(held, lot.taken(size));
+            assert_eq!(held, lot.capacity(size));
+        }
+
+        // No spot was handed to two cars
+        let mut spots: Vec<String> = tickets.iter().map(|ticket| ticket.spot.to_string()).collect();
+        spots.sort();
+        spots.dedup();
+        assert_eq!(spots.len(), tickets.len());
+    }
+
+    #[test]
+    fn a_failed_snapshot_keeps_the_journaled_change() {
+        let dir = std::env::temp_dir().join(format!("parking-snapshot-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut lot = ParkingSystem::new(2, 2, 2);
+        lot.set_verbose(false);
+        lot.enable_persistence(&dir, 1).unwrap();
+
+        // Something sitting where the snapshot's temp file goes
+        let blocker = dir.join(format!("{}.tmp", SNAPSHOT_FILE));
+        fs::create_dir(&blocker).unwrap();
+
+        let stays = lot.add_car(CarSize::Small).unwrap();
+        let goes = lot.add_car(CarSize::Big).unwrap();
+        lot.remove_car(goes).unwrap();
+        let failures = |lot: &ParkingSystem| lot.events().iter().filter(|e| matches!(e, Event::SnapshotFailed(_))).count();
+        assert_eq!(failures(&lot), 3);
+        assert_eq!(lot.ticket_at(stays.spot), Some(stays));
+        assert_eq!(lot.ticket_at(goes.spot), None);
+
+        // The journal has all three, so recovery comes back to the same lot
+        assert_eq!(ParkingSystem::recover(&dir, 1).unwrap().snapshot(), lot.snapshot());
+
+        // Next entry retries the snapshot, and once it's in the journal is emptied out
+        fs::remove_dir(&blocker).unwrap();
+        lot.add_car(CarSize::Medium).unwrap();
+        assert_eq!(failures(&lot), 3);
+        assert_eq!(fs::metadata(dir.join(JOURNAL_FILE)).unwrap().len(), 0);
+        assert_eq!(ParkingSystem::recover(&dir, 1).unwrap().snapshot(), lot.snapshot());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_resize_that_cant_be_snapshotted_is_undone() {
+        let dir = std::env::temp_dir().join(format!("parking-resize-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut lot = ParkingSystem::new(1, 1, 1);
+        lot.set_verbose(false);
+        lot.enable_persistence(&dir, 10).unwrap();
+
+        let blocker = dir.join(format!("{}.tmp", SNAPSHOT_FILE));
+        fs::create_dir(&blocker).unwrap();
+        assert!(matches!(lot.resize(0, CarSize::Small, 3), Err(ParkingError::Io(_))));
+        assert_eq!(lot.free_spots(0, CarSize::Small), Some(1));
+
+        // Parking goes into the layout that's on disk, so recovery still lines up
+        let small = lot.add_car(CarSize::Small).unwrap();
+        assert!(lot.add_car(CarSize::Small).is_none());
+        assert_eq!(ParkingSystem::recover(&dir, 10).unwrap().snapshot(), lot.snapshot());
+
+        // Once the snapshot can be written the resize goes through, and recovery sees the new spots
+        fs::remove_dir(&blocker).unwrap();
+        lot.resize(0, CarSize::Small, 3).unwrap();
+        assert_eq!(lot.free_spots(0, CarSize::Small), Some(2));
+        lot.add_car(CarSize::Small).unwrap();
+        assert_eq!(ParkingSystem::recover(&dir, 10).unwrap().snapshot(), lot.snapshot());
+        assert_eq!(lot.ticket_at(small.spot), Some(small));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recovery_reproduces_the_lot_byte_for_byte() {
+        let dir = std::env::temp_dir().join(format!("parking-recovery-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut lot = ParkingSystem::with_levels(&[(2, 2, 2), (1, 1, 1)]);
+        lot.set_verbose(false);
+        lot.enable_persistence(&dir, 4).unwrap();
+
+        let mut tickets: Vec<Ticket> = CarSize::ALL.iter().cycle().take(8).filter_map(|&size| lot.add_car(size)).collect();
+        lot.remove_car(tickets.remove(1)).unwrap();
+        lot.remove_car(tickets.remove(3)).unwrap();
+        lot.add_car(CarSize::Medium).unwrap();
+
+        // A half-written line from the crash must not make it into the recovered lot
+        let before = lot.snapshot();
+        drop(lot);
+        let mut journal = OpenOptions::new().append(true).open(dir.join(JOURNAL_FILE)).unwrap();
+        write!(journal, "{{\"seq\":99,\"op\":\"pa").unwrap();
+
+        let recovered = ParkingSystem::recover(&dir, 4).unwrap();
+        assert_eq!(recovered.snapshot(), before);
+
+        // Only the torn line was cut, the whole entries after the last snapshot are still there
+        let journal = fs::read_to_string(dir.join(JOURNAL_FILE)).unwrap();
+        assert!(journal.ends_with('\n'));
+        assert_eq!(journal.lines().count(), 3);
+
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -1,12 +1,227 @@
-//  Didn't get to test this because I'm waiting on my new computer.
+// Chapter 6 Code... cargo test runs the checks at the bottom
+
+use std::cell::Cell;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::hash::Hash;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::Mutex;
+use std::thread;
+use std::rc::Rc;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+// Which field was bad and why, so a rejected selector can be reported back to whoever typed it in
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum SelectorError {
+    ImeiLength(String),
+    ImeiNotNumeric(String),
+    ImeiChecksum(String),
+    InvalidIpv4(String),
+    InvalidIpv6(String),
+    InvalidCidr(String),
+    MissingField(&'static str),
+    UnexpectedField(String),
+}
+
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SelectorError::ImeiLength(imei) => write!(f, "imei {} should be 15 digits", imei),
+            SelectorError::ImeiNotNumeric(imei) => write!(f, "imei {} has something other than digits in it", imei),
+            SelectorError::ImeiChecksum(imei) => write!(f, "imei {} fails the Luhn check", imei),
+            SelectorError::InvalidIpv4(ipv4) => write!(f, "{} isn't an ipv4 address", ipv4),
+            SelectorError::InvalidIpv6(ipv6) => write!(f, "{} isn't an ipv6 address", ipv6),
+            SelectorError::InvalidCidr(cidr) => write!(f, "{} isn't a subnet, expected address/prefix", cidr),
+            SelectorError::MissingField(field) => write!(f, "selector is missing its {}", field),
+            SelectorError::UnexpectedField(field) => write!(f, "{} isn't part of a selector, only subnets can follow imei,ipv4,ipv6", field),
+        }
+    }
+}
+
+impl Error for SelectorError {}
+
+// 14 digits plus a Luhn check digit... only ever built through FromStr, so it's always valid
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct Imei(String);
+
+impl FromStr for Imei {
+    type Err = SelectorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let imei = s.trim();
+
+        if !imei.chars().all(|c| c.is_ascii_digit()) {
+            return Err(SelectorError::ImeiNotNumeric(imei.to_string()));
+        }
+        if imei.len() != 15 {
+            return Err(SelectorError::ImeiLength(imei.to_string()));
+        }
+
+        // Luhn: double every second digit from the right, digits of the products summed, total ends in 0
+        let sum: u32 = imei
+            .chars()
+            .rev()
+            .filter_map(|c| c.to_digit(10))
+            .enumerate()
+            .map(|(i, digit)| if i % 2 == 1 { (digit * 2) / 10 + (digit * 2) % 10 } else { digit })
+            .sum();
+
+        if !sum.is_multiple_of(10) {
+            return Err(SelectorError::ImeiChecksum(imei.to_string()));
+        }
+
+        Ok(Imei(imei.to_string()))
+    }
+}
+
+impl fmt::Display for Imei {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// A subnet like 10.0.0.0/8 or fe80::/10
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Cidr {
+    V4 { network: Ipv4Addr, prefix: u8 },
+    V6 { network: Ipv6Addr, prefix: u8 },
+}
+
+impl Cidr {
+    fn v4_mask(prefix: u8) -> u32 {
+        u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0)
+    }
+
+    fn v6_mask(prefix: u8) -> u128 {
+        u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0)
+    }
+
+    fn contains(&self, addr: &IpAddr) -> bool {
+        match (self, addr) {
+            (Cidr::V4 { network, prefix }, IpAddr::V4(addr)) => {
+                let mask = Cidr::v4_mask(*prefix);
+                u32::from(*network) & mask == u32::from(*addr) & mask
+            }
+            (Cidr::V6 { network, prefix }, IpAddr::V6(addr)) => {
+                let mask = Cidr::v6_mask(*prefix);
+                u128::from(*network) & mask == u128::from(*addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = SelectorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || SelectorError::InvalidCidr(s.to_string());
+        let (network, prefix) = s.trim().split_once('/').ok_or_else(invalid)?;
+        let prefix: u8 = prefix.parse().map_err(|_| invalid())?;
+
+        // Drop the host bits so 10.0.0.7/24 and 10.0.0.0/24 are the same selector
+        match network.parse::<IpAddr>().map_err(|_| invalid())? {
+            IpAddr::V4(network) if prefix <= 32 => Ok(Cidr::V4 { network: Ipv4Addr::from(u32::from(network) & Cidr::v4_mask(prefix)), prefix }),
+            IpAddr::V6(network) if prefix <= 128 => Ok(Cidr::V6 { network: Ipv6Addr::from(u128::from(network) & Cidr::v6_mask(prefix)), prefix }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Cidr::V4 { network, prefix } => write!(f, "{}/{}", network, prefix),
+            Cidr::V6 { network, prefix } => write!(f, "{}/{}", network, prefix),
+        }
+    }
+}
 
 struct SelectorAttributes {
-    imei: String,
-    ipv4: (u8, u8, u8, u8),
-    ipv6: String,
+    imei: Imei,
+    ipv4: Ipv4Addr,
+    ipv6: Ipv6Addr,
+    subnets: Vec<Cidr>, // whole ranges to watch on top of the two addresses
     // etc
 }
 
+impl SelectorAttributes {
+    fn new(imei: &str, ipv4: &str, ipv6: &str) -> Result<Self, SelectorError> {
+        Ok(Self {
+            imei: imei.parse()?,
+            ipv4: ipv4.trim().parse().map_err(|_| SelectorError::InvalidIpv4(ipv4.to_string()))?,
+            ipv6: ipv6.trim().parse().map_err(|_| SelectorError::InvalidIpv6(ipv6.to_string()))?,
+            subnets: Vec::new(),
+        })
+    }
+
+    fn with_subnet(mut self, cidr: Cidr) -> Self {
+        self.subnets.push(cidr);
+        self
+    }
+
+    fn keys(&self) -> Vec<SelectorKey> {
+        let mut keys = vec![
+            SelectorKey::Imei(self.imei.clone()),
+            SelectorKey::Ipv4(self.ipv4),
+            SelectorKey::Ipv6(self.ipv6),
+        ];
+        keys.extend(self.subnets.iter().map(|&cidr| SelectorKey::Subnet(cidr)));
+        keys
+    }
+
+    // True if either address falls inside the subnet
+    fn in_subnet(&self, cidr: &Cidr) -> bool {
+        cidr.contains(&IpAddr::V4(self.ipv4)) || cidr.contains(&IpAddr::V6(self.ipv6))
+    }
+}
+
+// "imei,ipv4,ipv6" then any subnets, e.g. 490154203237518,10.0.0.7,fe80::1,10.0.0.0/24
+impl FromStr for SelectorAttributes {
+    type Err = SelectorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split(',');
+        let imei = fields.next().filter(|field| !field.trim().is_empty()).ok_or(SelectorError::MissingField("imei"))?;
+        let ipv4 = fields.next().ok_or(SelectorError::MissingField("ipv4"))?;
+        let ipv6 = fields.next().ok_or(SelectorError::MissingField("ipv6"))?;
+
+        let mut selector = Self::new(imei, ipv4, ipv6)?;
+        for field in fields {
+            let cidr = field.parse().map_err(|_| SelectorError::UnexpectedField(field.to_string()))?;
+            selector = selector.with_subnet(cidr);
+        }
+
+        Ok(selector)
+    }
+}
+
+// One selector value on its own, so hits can be counted per source
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum SelectorKey {
+    Imei(Imei),
+    Ipv4(Ipv4Addr),
+    Ipv6(Ipv6Addr),
+    Subnet(Cidr),
+}
+
+impl fmt::Display for SelectorKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SelectorKey::Imei(imei) => write!(f, "imei:{}", imei),
+            SelectorKey::Ipv4(ipv4) => write!(f, "ipv4:{}", ipv4),
+            SelectorKey::Ipv6(ipv6) => write!(f, "ipv6:{}", ipv6),
+            SelectorKey::Subnet(cidr) => write!(f, "subnet:{}", cidr),
+        }
+    }
+}
+
 struct Operation {
     team_name: String,
     selectors_concerned_with: Option<SelectorAttributes>,
@@ -18,6 +233,190 @@ enum CyberDefenseTeam {
     Gaza(Operation),
 }
 
+impl CyberDefenseTeam {
+    fn operation(&self) -> &Operation {
+        match self {
+            CyberDefenseTeam::Israel(operation) | CyberDefenseTeam::Gaza(operation) => operation,
+        }
+    }
+}
+
+////////////////////////////
+///// Mission Registry /////
+////////////////////////////
+
+type OperationId = u64;
+
+// The same selector claimed by operations from more than one team
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Conflict {
+    selector: SelectorKey,
+    teams: Vec<String>,
+    operations: Vec<OperationId>,
+}
+
+// Every operation by id, plus indexes by selector, capability and team that add/remove keep in step.
+// Subnets get their own index since an address is matched against every one of them, not looked up
+struct MissionRegistry {
+    next_id: OperationId,
+    operations: HashMap<OperationId, CyberDefenseTeam>,
+    by_selector: HashMap<SelectorKey, BTreeSet<OperationId>>,
+    by_subnet: HashMap<Cidr, BTreeSet<OperationId>>,
+    by_capability: HashMap<String, BTreeSet<OperationId>>,
+    by_team: HashMap<String, BTreeSet<OperationId>>,
+}
+
+impl MissionRegistry {
+
+    fn new() -> Self {
+        Self {
+            next_id: 1,
+            operations: HashMap::new(),
+            by_selector: HashMap::new(),
+            by_subnet: HashMap::new(),
+            by_capability: HashMap::new(),
+            by_team: HashMap::new(),
+        }
+    }
+
+    // (selectors, capabilities, team) an operation is indexed under... capabilities are matched case-insensitively
+    fn index_keys(operation: &Operation) -> (Vec<SelectorKey>, Vec<String>, String) {
+        let selectors = operation.selectors_concerned_with.as_ref().map(|selectors| selectors.keys()).unwrap_or_default();
+        let capabilities = operation
+            .mission_capes
+            .iter()
+            .flatten()
+            .map(|capability| capability.trim().to_lowercase())
+            .collect();
+
+        (selectors, capabilities, operation.team_name.clone())
+    }
+
+    fn add(&mut self, team: CyberDefenseTeam) -> OperationId {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let (selectors, capabilities, team_name) = Self::index_keys(team.operation());
+        for selector in selectors {
+            match selector {
+                SelectorKey::Subnet(cidr) => self.by_subnet.entry(cidr).or_default().insert(id),
+                selector => self.by_selector.entry(selector).or_default().insert(id),
+            };
+        }
+        for capability in capabilities {
+            self.by_capability.entry(capability).or_default().insert(id);
+        }
+        self.by_team.entry(team_name).or_default().insert(id);
+
+        self.operations.insert(id, team);
+        id
+    }
+
+    fn remove(&mut self, id: OperationId) -> Option<CyberDefenseTeam> {
+        let team = self.operations.remove(&id)?;
+
+        // Drop the id from every index it was in, and the index entry itself once nobody's left
+        fn unindex<K: Hash + Eq>(index: &mut HashMap<K, BTreeSet<OperationId>>, key: K, id: OperationId) {
+            if let Some(ids) = index.get_mut(&key) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    index.remove(&key);
+                }
+            }
+        }
+
+        let (selectors, capabilities, team_name) = Self::index_keys(team.operation());
+        for selector in selectors {
+            match selector {
+                SelectorKey::Subnet(cidr) => unindex(&mut self.by_subnet, cidr, id),
+                selector => unindex(&mut self.by_selector, selector, id),
+            }
+        }
+        for capability in capabilities {
+            unindex(&mut self.by_capability, capability, id);
+        }
+        unindex(&mut self.by_team, team_name, id);
+
+        Some(team)
+    }
+
+    fn get(&self, id: OperationId) -> Option<&CyberDefenseTeam> {
+        self.operations.get(&id)
+    }
+
+    // Which operations are watching this IMEI / IPv4 / IPv6 / subnet... an address also matches every subnet it's in
+    fn watching(&self, selector: &SelectorKey) -> Vec<OperationId> {
+        let addr = match selector {
+            SelectorKey::Ipv4(ipv4) => Some(IpAddr::V4(*ipv4)),
+            SelectorKey::Ipv6(ipv6) => Some(IpAddr::V6(*ipv6)),
+            SelectorKey::Imei(_) | SelectorKey::Subnet(_) => None,
+        };
+        let exact = match selector {
+            SelectorKey::Subnet(cidr) => self.by_subnet.get(cidr),
+            selector => self.by_selector.get(selector),
+        };
+
+        let ids: BTreeSet<OperationId> = exact
+            .into_iter()
+            .chain(self.by_subnet.iter().filter(|(cidr, _)| addr.is_some_and(|addr| cidr.contains(&addr))).map(|(_, ids)| ids))
+            .flatten()
+            .copied()
+            .collect();
+
+        ids.into_iter().collect()
+    }
+
+    fn operations_for_team(&self, team_name: &str) -> Vec<OperationId> {
+        self.by_team.get(team_name).map(|ids| ids.iter().copied().collect()).unwrap_or_default()
+    }
+
+    // Which teams have at least one operation with this capability, sorted by name
+    fn teams_with_capability(&self, capability: &str) -> Vec<String> {
+        let teams: BTreeSet<String> = self
+            .by_capability
+            .get(&capability.trim().to_lowercase())
+            .into_iter()
+            .flatten()
+            .filter_map(|id| self.operations.get(id))
+            .map(|team| team.operation().team_name.clone())
+            .collect();
+
+        teams.into_iter().collect()
+    }
+
+    // Selectors owned by more than one team... the same team running several operations on one selector is fine
+    fn conflicts(&self) -> Vec<Conflict> {
+        let subnets = self.by_subnet.iter().map(|(&cidr, ids)| (SelectorKey::Subnet(cidr), ids));
+
+        let mut conflicts: Vec<Conflict> = self
+            .by_selector
+            .iter()
+            .map(|(selector, ids)| (selector.clone(), ids))
+            .chain(subnets)
+            .filter_map(|(selector, ids)| {
+                let teams: BTreeSet<String> = ids
+                    .iter()
+                    .filter_map(|id| self.operations.get(id))
+                    .map(|team| team.operation().team_name.clone())
+                    .collect();
+
+                if teams.len() < 2 {
+                    return None;
+                }
+
+                Some(Conflict {
+                    selector,
+                    teams: teams.into_iter().collect(),
+                    operations: ids.iter().copied().collect(),
+                })
+            })
+            .collect();
+
+        conflicts.sort_by_key(|conflict| conflict.selector.to_string());
+        conflicts
+    }
+}
+
 struct Hit {
     timestamp: i32,
     count: i32,
@@ -49,11 +448,12 @@ impl Hit {
     }
 }
 
+// Buckets are one per second, oldest at the front... anything that slides out of the window is popped off
 struct HitCounter {
-    hits: Vec<Hit>,
+    window: i32,
+    hits: VecDeque<Hit>,
 }
 
-
 /** 
  * Client can implement their mission areas with the cyber defense team enum... etc...
  * Funny story, I got the CISSP and applied for a CDT with the Navy in 2020... Apparently, time worked in my favor. Hope to share my knowledge with newbie programmers/cyber ops professionals in the future. 
@@ -61,36 +461,61 @@ struct HitCounter {
 **/
 impl HitCounter {
 
-    fn new() -> Self {
-        Self {
-            hits: Vec::new(),
+    // window is in seconds, the Leetcode version always used 300
+    fn new(window: i32) -> Result<Self, String> {
+        if window <= 0 {
+            return Err(format!("a {} second window can't hold any hits", window));
         }
+
+        Ok(Self {
+            window,
+            hits: VecDeque::new(),
+        })
     }
     
     /*
-        - Vec::last_mut() returns an Option<&mut T>
+        - VecDeque::back_mut() returns an Option<&mut T>
         - if let combines a match statement with a pattern, it returns the value inside Some else the block is skipped
-        - if let Some(last_hit) = self.hits.last_mut() -> gives you a mutable reference if it exists in the last element
+        - if let Some(last_hit) = self.hits.back_mut() -> gives you a mutable reference if it exists in the last element
+        - Timestamps only move forward, so at most `window` buckets are ever alive
     */
-    fn hit(&mut self, timestamp: i32) {
-        if let Some(last_hit) = self.hits.last_mut() {
+    fn hit(&mut self, timestamp: i32) -> Result<(), String> {
+        if let Some(last_hit) = self.hits.back_mut() {
+            if timestamp < last_hit.get() {
+                return Err(format!("{} arrived after {}... hits have to come in order", timestamp, last_hit.get()));
+            }
             if last_hit.get() == timestamp {
                 last_hit.update();
-                return;
+                return Ok(());
             }
         }
 
-        self.hits.push(Hit::new(timestamp).unwrap());
+        self.hits.push_back(Hit::new(timestamp)?);
+        self.evict(timestamp);
+
+        Ok(())
+    }
+
+    fn evict(&mut self, timestamp: i32) {
+        while let Some(oldest) = self.hits.front() {
+            if timestamp - oldest.get() < self.window {
+                break;
+            }
+            self.hits.pop_front();
+        }
     }
     
     fn get_hits(&self, timestamp: i32) -> i32 {
         let mut hits = 0;
 
-        // Option here: for idx in (0..self.length).rev() {
+        // Option here: for idx in (0..self.hits.len()).rev() {
         for item in self.hits.iter().rev() {
             let diff = timestamp - item.get();
 
-            if diff < 300 && diff >= 0 {
+            if diff < 0 {
+                continue;
+            }
+            if diff < self.window {
                 hits += item.hits();
             } else {
                 break;
@@ -99,6 +524,1406 @@ impl HitCounter {
 
         hits
     }
+
+    fn buckets(&self) -> usize {
+        self.hits.len()
+    }
+}
+
+/*
+    - Same idea as HitCounter, but hit() takes &self so every sensor thread can share one counter without a lock
+    - One AtomicU64 per second of the window, used as a ring: slot = timestamp % window
+    - Each slot packs (timestamp << 32) | count, so the second and its count always change together
+        - A reader can never see a fresh count paired with a stale second (or the other way around)
+    - Relaxed is enough: every slot has its own total modification order and nothing else is published through it
+*/
+struct ConcurrentHitCounter {
+    window: i32,
+    buckets: Vec<AtomicU64>,
+}
+
+impl ConcurrentHitCounter {
+
+    fn new(window: i32) -> Result<Self, String> {
+        if window <= 0 {
+            return Err(format!("a {} second window can't hold any hits", window));
+        }
+
+        Ok(Self {
+            window,
+            buckets: (0..window).map(|_| AtomicU64::new(0)).collect(),
+        })
+    }
+
+    fn pack(timestamp: i32, count: u64) -> u64 {
+        ((timestamp as u64) << 32) | count
+    }
+
+    fn unpack(bucket: u64) -> (i32, u64) {
+        ((bucket >> 32) as i32, bucket & u32::MAX as u64)
+    }
+
+    // Compare-and-exchange loop: bump the count if the slot already holds this second, otherwise reclaim it
+    fn hit(&self, timestamp: i32) -> Result<(), String> {
+        if timestamp < 0 {
+            return Err(format!("{} isnt a valid hit... unless its though a quantum space bridge... hmm...", timestamp));
+        }
+
+        let slot = &self.buckets[(timestamp % self.window) as usize];
+        let mut current = slot.load(Relaxed);
+
+        loop {
+            let (second, count) = Self::unpack(current);
+
+            let new = if second == timestamp && count > 0 {
+                Self::pack(timestamp, count + 1)
+            } else if second < timestamp || count == 0 {
+                Self::pack(timestamp, 1)
+            } else {
+                return Err(format!("{} is a whole window behind {}... too late to count", timestamp, second));
+            };
+
+            match slot.compare_exchange_weak(current, new, Relaxed, Relaxed) {
+                Ok(_) => return Ok(()),
+                Err(v) => current = v,
+            }
+        }
+    }
+
+    // Every slot is read in one load, so a bucket is counted whole or not at all
+    fn get_hits(&self, timestamp: i32) -> u64 {
+        self.buckets
+            .iter()
+            .map(|slot| Self::unpack(slot.load(Relaxed)))
+            .filter(|&(second, count)| count > 0 && second <= timestamp && timestamp - second < self.window)
+            .map(|(_, count)| count)
+            .sum()
+    }
+}
+
+// An exact sliding window per source... memory grows with the number of sources seen in the last window
+struct KeyedHitCounter<K> {
+    window: i32,
+    counters: HashMap<K, HitCounter>,
+}
+
+impl<K: Hash + Eq + Clone + Ord> KeyedHitCounter<K> {
+
+    fn new(window: i32) -> Result<Self, String> {
+        // Borrow HitCounter's check on the window
+        HitCounter::new(window)?;
+
+        Ok(Self {
+            window,
+            counters: HashMap::new(),
+        })
+    }
+
+    fn hit(&mut self, key: &K, timestamp: i32) -> Result<(), String> {
+        if !self.counters.contains_key(key) {
+            self.counters.insert(key.clone(), HitCounter::new(self.window)?);
+        }
+
+        self.counters.get_mut(key).unwrap().hit(timestamp)
+    }
+
+    fn get_hits(&self, key: &K, timestamp: i32) -> i32 {
+        self.counters.get(key).map_or(0, |counter| counter.get_hits(timestamp))
+    }
+
+    // Sources that went quiet for a whole window are dropped, call this every so often
+    fn prune(&mut self, timestamp: i32) {
+        self.counters.retain(|_, counter| counter.get_hits(timestamp) > 0);
+    }
+
+    // The n busiest sources in the window ending at timestamp, busiest first and ties in key order
+    fn top(&self, n: usize, timestamp: i32) -> Vec<(K, i32)> {
+        let mut ranked: Vec<(K, i32)> = self
+            .counters
+            .keys()
+            .map(|key| (key.clone(), self.get_hits(key, timestamp)))
+            .filter(|&(_, hits)| hits > 0)
+            .collect();
+
+        ranked.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        ranked.truncate(n);
+        ranked
+    }
+}
+
+/*
+    Space-Saving (Metwally et al.): keeps at most `capacity` keys no matter how many show up
+    - A new key when full replaces the smallest count and inherits it (+1), that inherited part is the error
+    - Any key with more than total / capacity hits is guaranteed to be in the table
+    - count is an overestimate, count - error is an underestimate
+*/
+struct SpaceSaving<K> {
+    capacity: usize,
+    counts: HashMap<K, (u64, u64)>, // key -> (count, error)
+}
+
+impl<K: Hash + Eq + Clone> SpaceSaving<K> {
+
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            counts: HashMap::with_capacity(capacity),
+        }
+    }
+
+    fn offer(&mut self, key: &K) {
+        if let Some((count, _)) = self.counts.get_mut(key) {
+            *count += 1;
+            return;
+        }
+
+        if self.counts.len() < self.capacity {
+            self.counts.insert(key.clone(), (1, 0));
+            return;
+        }
+
+        let smallest = self
+            .counts
+            .iter()
+            .min_by_key(|(_, &(count, _))| count)
+            .map(|(key, &(count, _))| (key.clone(), count));
+
+        if let Some((evicted, min)) = smallest {
+            self.counts.remove(&evicted);
+            self.counts.insert(key.clone(), (min + 1, min));
+        }
+    }
+}
+
+// One Space-Saving table per second, same buckets as HitCounter... top() merges the ones still inside the window,
+// so it covers exactly the last `window` seconds. Each table's error adds up, so the bound is per table times seconds
+struct HeavyHitters<K> {
+    window: i32,
+    capacity: usize,
+    seconds: VecDeque<(i32, SpaceSaving<K>)>,
+}
+
+impl<K: Hash + Eq + Clone + Ord> HeavyHitters<K> {
+
+    fn new(window: i32, capacity: usize) -> Result<Self, String> {
+        if capacity == 0 {
+            return Err(String::from("a heavy hitters table needs room for at least one source"));
+        }
+        HitCounter::new(window)?;
+
+        Ok(Self {
+            window,
+            capacity,
+            seconds: VecDeque::new(),
+        })
+    }
+
+    fn hit(&mut self, key: &K, timestamp: i32) -> Result<(), String> {
+        if timestamp < 0 {
+            return Err(format!("{} isnt a valid hit... unless its though a quantum space bridge... hmm...", timestamp));
+        }
+
+        match self.seconds.back_mut() {
+            Some((second, _)) if timestamp < *second => {
+                return Err(format!("{} arrived after {}... hits have to come in order", timestamp, second));
+            }
+            Some((second, table)) if *second == timestamp => table.offer(key),
+            _ => {
+                let mut table = SpaceSaving::new(self.capacity);
+                table.offer(key);
+                self.seconds.push_back((timestamp, table));
+            }
+        }
+
+        while let Some((oldest, _)) = self.seconds.front() {
+            if timestamp - oldest < self.window {
+                break;
+            }
+            self.seconds.pop_front();
+        }
+
+        Ok(())
+    }
+
+    // (key, estimated hits, guaranteed hits) in the window ending at timestamp, busiest first and ties in key order
+    fn top(&self, n: usize, timestamp: i32) -> Vec<(K, u64, u64)> {
+        let mut merged: HashMap<K, (u64, u64)> = HashMap::new();
+
+        for (second, table) in &self.seconds {
+            let diff = timestamp - second;
+            if diff < 0 || diff >= self.window {
+                continue;
+            }
+            for (key, &(count, error)) in &table.counts {
+                let entry = merged.entry(key.clone()).or_insert((0, 0));
+                entry.0 += count;
+                entry.1 += error;
+            }
+        }
+
+        let mut ranked: Vec<(K, u64, u64)> = merged
+            .into_iter()
+            .map(|(key, (count, error))| (key, count, count - error))
+            .collect();
+
+        ranked.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        ranked.truncate(n);
+        ranked
+    }
+}
+
+////////////////////////////
+////// Rate Limiting ///////
+////////////////////////////
+
+// Seconds, same as Hit timestamps
+trait Clock {
+    fn now(&self) -> i32;
+}
+
+// Counts from when it was made instead of 1970, so it never steps backwards and an i32 lasts 68 years
+struct SystemClock {
+    started: Instant,
+}
+
+impl SystemClock {
+    fn new() -> Self {
+        Self { started: Instant::now() }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> i32 {
+        i32::try_from(self.started.elapsed().as_secs()).unwrap_or(i32::MAX)
+    }
+}
+
+// Clones share the same time, keep one to move the limiter's clock along in tests
+#[derive(Clone, Default)]
+struct MockClock {
+    now: Rc<Cell<i32>>,
+}
+
+impl MockClock {
+    fn new(start: i32) -> Self {
+        Self { now: Rc::new(Cell::new(start)) }
+    }
+
+    fn advance(&self, secs: i32) {
+        self.now.set(self.now.get() + secs);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> i32 {
+        self.now.get()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Decision {
+    Allow,
+    Deny { retry_after: i32 }, // seconds until the same request would be let through
 }
 
-// fn main() later when computer arrives!!
+// At most `max` requests every `per` seconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Limit {
+    max: i32,
+    per: i32,
+}
+
+impl Limit {
+    fn new(max: i32, per: i32) -> Result<Self, String> {
+        if max <= 0 || per <= 0 {
+            return Err(format!("{} requests every {} seconds isn't a limit", max, per));
+        }
+        // TokenBucket counts in 1/per of a request, so (max + 1) * per has to fit
+        if max.checked_add(1).and_then(|max| max.checked_mul(per)).is_none() {
+            return Err(format!("{} requests every {} seconds is more than a limiter can keep track of", max, per));
+        }
+
+        Ok(Self { max, per })
+    }
+}
+
+trait RateLimiter<K> {
+    fn check(&mut self, key: &K) -> Decision;
+    fn set_limit(&mut self, key: K, limit: Limit);
+}
+
+// Per-key overrides on top of one default, shared by every limiter below
+struct Limits<K> {
+    default: Limit,
+    overrides: HashMap<K, Limit>,
+}
+
+impl<K: Hash + Eq> Limits<K> {
+    fn new(default: Limit) -> Self {
+        Self {
+            default,
+            overrides: HashMap::new(),
+        }
+    }
+
+    fn get(&self, key: &K) -> Limit {
+        self.overrides.get(key).copied().unwrap_or(self.default)
+    }
+}
+
+// Drops keys with nothing left to remember, at most once a second so a busy limiter isn't scanning on every check
+fn sweep<K, V>(map: &mut HashMap<K, V>, swept: &mut i32, now: i32, idle: impl Fn(&K, &V) -> bool) {
+    if now > *swept {
+        map.retain(|key, value| !idle(key, value));
+        *swept = now;
+    }
+}
+
+// Hit.timestamp is the start of the key's current window, Hit.count how much of it is used
+struct FixedWindow<K> {
+    limits: Limits<K>,
+    windows: HashMap<K, Hit>,
+    clock: Box<dyn Clock>,
+    swept: i32,
+}
+
+impl<K: Hash + Eq + Clone> FixedWindow<K> {
+    fn new(default: Limit, clock: Box<dyn Clock>) -> Self {
+        Self {
+            limits: Limits::new(default),
+            windows: HashMap::new(),
+            clock,
+            swept: 0,
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> RateLimiter<K> for FixedWindow<K> {
+    fn check(&mut self, key: &K) -> Decision {
+        let limit = self.limits.get(key);
+        let now = self.clock.now();
+        let start = now - now.rem_euclid(limit.per);
+
+        let limits = &self.limits;
+        sweep(&mut self.windows, &mut self.swept, now, |key, window| window.get() + limits.get(key).per <= now);
+
+        match self.windows.get_mut(key) {
+            Some(window) if window.get() == start => {
+                if window.hits() >= limit.max {
+                    return Decision::Deny { retry_after: start + limit.per - now };
+                }
+                window.update();
+            }
+            _ => match Hit::new(start) {
+                Ok(window) => {
+                    self.windows.insert(key.clone(), window);
+                }
+                Err(_) => return Decision::Deny { retry_after: 0 },
+            },
+        }
+
+        Decision::Allow
+    }
+
+    fn set_limit(&mut self, key: K, limit: Limit) {
+        self.limits.overrides.insert(key, limit);
+    }
+}
+
+// Every allowed request goes into the key's own HitCounter, so the window really slides
+struct SlidingLog<K> {
+    limits: Limits<K>,
+    logs: HashMap<K, HitCounter>,
+    clock: Box<dyn Clock>,
+    swept: i32,
+}
+
+impl<K: Hash + Eq + Clone> SlidingLog<K> {
+    fn new(default: Limit, clock: Box<dyn Clock>) -> Self {
+        Self {
+            limits: Limits::new(default),
+            logs: HashMap::new(),
+            clock,
+            swept: 0,
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> RateLimiter<K> for SlidingLog<K> {
+    fn check(&mut self, key: &K) -> Decision {
+        let limit = self.limits.get(key);
+
+        let now = self.clock.now();
+        sweep(&mut self.logs, &mut self.swept, now, |_, log| log.hits.back().is_none_or(|last| now - last.get() >= log.window));
+
+        // A limit change means a different window length, so start that key's log over
+        let stale = self.logs.get(key).is_none_or(|log| log.window != limit.per);
+        if stale {
+            match HitCounter::new(limit.per) {
+                Ok(log) => self.logs.insert(key.clone(), log),
+                Err(_) => return Decision::Deny { retry_after: 0 },
+            };
+        }
+        let log = self.logs.get_mut(key).unwrap();
+
+        // A clock that steps backwards is counted at the latest second seen
+        let now = log.hits.back().map_or(now, |last| last.get().max(now));
+        let used = log.get_hits(now);
+
+        if used < limit.max {
+            return match log.hit(now) {
+                Ok(()) => Decision::Allow,
+                Err(_) => Decision::Deny { retry_after: 0 },
+            };
+        }
+
+        // Wait for just enough of the oldest hits to slide out
+        let mut over = used - limit.max;
+        for bucket in log.hits.iter().filter(|bucket| now - bucket.get() < limit.per) {
+            if bucket.hits() > over {
+                return Decision::Deny { retry_after: bucket.get() + limit.per - now };
+            }
+            over -= bucket.hits();
+        }
+
+        Decision::Deny { retry_after: limit.per }
+    }
+
+    fn set_limit(&mut self, key: K, limit: Limit) {
+        self.limits.overrides.insert(key, limit);
+    }
+}
+
+// Up to `max` requests in a burst, refilled smoothly at max / per requests a second.
+// Hit.timestamp is the last refill and Hit.count how much of the bucket is spent, counted in 1/per of a request
+// so a request costs `per` and every second gives back `max`... no fractions to round
+struct TokenBucket<K> {
+    limits: Limits<K>,
+    buckets: HashMap<K, Hit>,
+    clock: Box<dyn Clock>,
+    swept: i32,
+}
+
+impl<K: Hash + Eq + Clone> TokenBucket<K> {
+    fn new(default: Limit, clock: Box<dyn Clock>) -> Self {
+        Self {
+            limits: Limits::new(default),
+            buckets: HashMap::new(),
+            clock,
+            swept: 0,
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> RateLimiter<K> for TokenBucket<K> {
+    fn check(&mut self, key: &K) -> Decision {
+        let limit = self.limits.get(key);
+        let size = limit.max * limit.per;
+        let now = self.clock.now();
+
+        // Full again means there's nothing to remember
+        let limits = &self.limits;
+        sweep(&mut self.buckets, &mut self.swept, now, |key, bucket| {
+            (now - bucket.get()).saturating_mul(limits.get(key).max) >= bucket.hits()
+        });
+
+        if !self.buckets.contains_key(key) {
+            match Hit::new(now) {
+                Ok(mut bucket) => {
+                    bucket.count = 0;
+                    self.buckets.insert(key.clone(), bucket);
+                }
+                Err(_) => return Decision::Deny { retry_after: 0 },
+            }
+        }
+        let bucket = self.buckets.get_mut(key).unwrap();
+
+        // A clock that steps backwards refills nothing, a smaller limit can't leave more spent than there is
+        let refill = (now - bucket.get()).max(0).saturating_mul(limit.max);
+        bucket.count = (bucket.hits() - refill).clamp(0, size);
+        bucket.timestamp = now.max(bucket.get());
+
+        if bucket.hits() + limit.per <= size {
+            bucket.count += limit.per;
+            Decision::Allow
+        } else {
+            let short = bucket.hits() + limit.per - size;
+            Decision::Deny { retry_after: (short + limit.max - 1) / limit.max }
+        }
+    }
+
+    fn set_limit(&mut self, key: K, limit: Limit) {
+        self.limits.overrides.insert(key, limit);
+    }
+}
+
+////////////////////////////
+// Event Correlation ///////
+////////////////////////////
+
+// Same shape as the Messenger in smart_ptrs.rs, so a LimitTracker style sink drops right in
+trait Messenger {
+    fn send(&self, msg: &str);
+}
+
+struct StdoutMessenger;
+
+impl Messenger for StdoutMessenger {
+    fn send(&self, msg: &str) {
+        println!("ALERT {}", msg);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct NetworkEvent {
+    timestamp: i32,
+    src: IpAddr,
+    imei: Option<Imei>,
+}
+
+impl NetworkEvent {
+    fn new(timestamp: &str, src: &str, imei: &str) -> Result<Self, String> {
+        let imei = imei.trim();
+
+        Ok(Self {
+            timestamp: timestamp.trim().parse().map_err(|_| format!("{} isn't a timestamp", timestamp))?,
+            src: src.trim().parse().map_err(|_| format!("{} isn't an ip address", src))?,
+            imei: if imei.is_empty() { None } else { Some(imei.parse().map_err(|e: SelectorError| e.to_string())?) },
+        })
+    }
+
+    // timestamp,src,imei... imei can be left empty
+    fn from_csv(line: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = line.split(',').collect();
+
+        match fields.as_slice() {
+            [timestamp, src, imei] => Self::new(timestamp, src, imei),
+            [timestamp, src] => Self::new(timestamp, src, ""),
+            _ => Err(String::from("expected timestamp,src,imei")),
+        }
+    }
+
+    // {"timestamp": 12, "src": "10.0.0.7", "imei": "490154203237518"}
+    fn from_json(line: &str) -> Result<Self, String> {
+        let timestamp = json_field(line, "timestamp").ok_or("missing timestamp")?;
+        let src = json_field(line, "src").ok_or("missing src")?;
+        let imei = json_field(line, "imei").unwrap_or("");
+
+        Self::new(timestamp, src, imei)
+    }
+
+    fn to_csv(&self) -> String {
+        let imei = self.imei.as_ref().map(|imei| imei.to_string()).unwrap_or_default();
+        format!("{},{},{}", self.timestamp, self.src, imei)
+    }
+}
+
+// Value of a key in a flat JSON object, quotes stripped... nested objects and escapes aren't supported
+fn json_field<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    let start = line.find(&format!("\"{}\"", key))? + key.len() + 2;
+    let rest = line[start..].trim_start().strip_prefix(':')?.trim_start();
+
+    if let Some(quoted) = rest.strip_prefix('"') {
+        quoted.find('"').map(|end| &quoted[..end])
+    } else {
+        let end = rest.find([',', '}']).unwrap_or(rest.len());
+        Some(rest[..end].trim())
+    }
+}
+
+// .csv (header optional) or .ndjson/.jsonl, one event per line, sorted by timestamp
+fn read_events(path: &Path) -> Result<Vec<NetworkEvent>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let csv = path.extension().is_some_and(|ext| ext == "csv");
+
+    let mut events = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || (csv && number == 0 && line.starts_with("timestamp")) {
+            continue;
+        }
+
+        let event = if csv { NetworkEvent::from_csv(line) } else { NetworkEvent::from_json(line) };
+        events.push(event.map_err(|e| format!("{} line {}: {}", path.display(), number + 1, e))?);
+    }
+
+    events.sort_by_key(|event| event.timestamp);
+    Ok(events)
+}
+
+// Runs events past every operation's selectors, one HitCounter per operation,
+// and tells the messenger once an operation crosses `threshold` hits inside `window`
+struct CorrelationEngine<'a, M: Messenger> {
+    registry: &'a MissionRegistry,
+    messenger: &'a M,
+    window: i32,
+    threshold: i32,
+    counters: HashMap<OperationId, HitCounter>,
+    alerting: BTreeSet<OperationId>,
+}
+
+impl<'a, M: Messenger> CorrelationEngine<'a, M> {
+
+    fn new(registry: &'a MissionRegistry, messenger: &'a M, window: i32, threshold: i32) -> Result<Self, String> {
+        HitCounter::new(window)?;
+        if threshold <= 0 {
+            return Err(format!("a threshold of {} would alert on silence", threshold));
+        }
+
+        Ok(Self {
+            registry,
+            messenger,
+            window,
+            threshold,
+            counters: HashMap::new(),
+            alerting: BTreeSet::new(),
+        })
+    }
+
+    // The operations this event matched
+    fn ingest(&mut self, event: &NetworkEvent) -> Result<Vec<OperationId>, String> {
+        let src = match event.src {
+            IpAddr::V4(ipv4) => SelectorKey::Ipv4(ipv4),
+            IpAddr::V6(ipv6) => SelectorKey::Ipv6(ipv6),
+        };
+
+        let mut matched: BTreeSet<OperationId> = self.registry.watching(&src).into_iter().collect();
+        if let Some(imei) = &event.imei {
+            matched.extend(self.registry.watching(&SelectorKey::Imei(imei.clone())));
+        }
+
+        for &id in &matched {
+            if !self.counters.contains_key(&id) {
+                self.counters.insert(id, HitCounter::new(self.window)?);
+            }
+            let counter = self.counters.get_mut(&id).unwrap();
+            counter.hit(event.timestamp)?;
+            let hits = counter.get_hits(event.timestamp);
+
+            // Alert once on the way up, then stay quiet until the count drops back under the threshold
+            if hits >= self.threshold && self.alerting.insert(id) {
+                let team = self.registry.get(id).map_or("unknown team", |team| team.operation().team_name.as_str());
+                self.messenger.send(&format!(
+                    "operation {} ({}): {} hits in {}s at t={} from {}",
+                    id, team, hits, self.window, event.timestamp, event.src
+                ));
+            }
+        }
+
+        // Anything that cooled off can alert again
+        let threshold = self.threshold;
+        let counters = &self.counters;
+        self.alerting.retain(|id| counters.get(id).is_some_and(|counter| counter.get_hits(event.timestamp) >= threshold));
+
+        Ok(matched.into_iter().collect())
+    }
+
+    // Feeds a recorded day back through in timestamp order... `speedup` of Some(3600.0) plays an hour a second, None as fast as possible
+    fn replay(&mut self, events: &[NetworkEvent], speedup: Option<f64>) -> Result<usize, String> {
+        let mut matched = 0;
+        let mut previous = events.first().map_or(0, |event| event.timestamp);
+
+        for event in events {
+            if let Some(speedup) = speedup {
+                let gap = (event.timestamp - previous).max(0) as f64 / speedup;
+                thread::sleep(Duration::from_secs_f64(gap));
+            }
+            previous = event.timestamp;
+
+            if !self.ingest(event)?.is_empty() {
+                matched += 1;
+            }
+        }
+
+        Ok(matched)
+    }
+}
+
+// Milliseconds since the start of the run stand in for seconds so a benchmark spans a few "seconds"
+fn tick(started: &Instant) -> i32 {
+    started.elapsed().as_millis() as i32
+}
+
+// Hammer a counter from `threads` sensors and time it
+fn bench(name: &str, threads: i32, hits_per_thread: i32, hit: impl Fn() + Sync) {
+    let started = Instant::now();
+
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                for _ in 0..hits_per_thread {
+                    hit();
+                }
+            });
+        }
+    });
+
+    println!("{}: {} hits in {:.2?}", name, threads * hits_per_thread, started.elapsed());
+}
+
+fn main() {
+    let mut counter = HitCounter::new(300).unwrap();
+
+    for timestamp in [1, 2, 3, 3, 300, 301] {
+        if let Err(e) = counter.hit(timestamp) {
+            println!("{}", e);
+        }
+    }
+    println!("Hits in the 300s up to 301: {}", counter.get_hits(301));
+
+    // Everything up to 300 slides out of the window here
+    counter.hit(600).unwrap();
+    println!("Hits in the 300s up to 600: {}", counter.get_hits(600));
+    println!("Buckets still held: {}", counter.buckets());
+
+    // Going backwards in time is turned away
+    if let Err(e) = counter.hit(5) {
+        println!("{}", e);
+    }
+    if let Err(e) = HitCounter::new(0) {
+        println!("{}", e);
+    }
+
+    // Who's been busiest? Exact per source, then bounded with Space-Saving
+    let suspect: SelectorAttributes = "490154203237518,10.0.0.7,fe80::1".parse().unwrap();
+    let mut per_source = KeyedHitCounter::new(300).unwrap();
+    let mut heavy = HeavyHitters::new(300, 16).unwrap();
+
+    for timestamp in 0..600 {
+        let noise = SelectorKey::Ipv4(Ipv4Addr::new(192, 168, (timestamp % 7) as u8, (timestamp % 13) as u8));
+        per_source.hit(&noise, timestamp).unwrap();
+        heavy.hit(&noise, timestamp).unwrap();
+
+        if timestamp % 2 == 0 {
+            for key in suspect.keys() {
+                per_source.hit(&key, timestamp).unwrap();
+                heavy.hit(&key, timestamp).unwrap();
+            }
+        }
+    }
+
+    per_source.prune(599);
+    println!("suspect's IPv4 in the last 300s: {}", per_source.get_hits(&SelectorKey::Ipv4(suspect.ipv4), 599));
+    for (key, hits) in per_source.top(3, 599) {
+        println!("exact\t{}\t{}", key, hits);
+    }
+    for (key, estimate, guaranteed) in heavy.top(3, 599) {
+        println!("sketch\t{}\t{} (at least {})", key, estimate, guaranteed);
+    }
+
+    // Selectors are checked on the way in, and can be matched against whole subnets
+    for input in ["490154203237519,10.0.0.7,fe80::1", "490154203237518,10.0.0.300,fe80::1", "49015420323751,10.0.0.7,fe80::1", "490154203237518"] {
+        if let Err(e) = input.parse::<SelectorAttributes>() {
+            println!("{}", e);
+        }
+    }
+    for subnet in ["10.0.0.0/8", "192.168.0.0/16", "fe80::/10"] {
+        let cidr: Cidr = subnet.parse().unwrap();
+        println!("suspect in {}: {}", cidr, suspect.in_subnet(&cidr));
+    }
+
+    // Who's watching what, and who's stepping on whose toes
+    let mut registry = MissionRegistry::new();
+    let watch = registry.add(CyberDefenseTeam::Israel(Operation {
+        team_name: String::from("CDT Red"),
+        selectors_concerned_with: Some("490154203237518,10.0.0.7,fe80::1".parse().unwrap()),
+        mission_capes: Some(vec![String::from("Hunt"), String::from("Forensics")]),
+    }));
+    registry.add(CyberDefenseTeam::Gaza(Operation {
+        team_name: String::from("CDT Blue"),
+        selectors_concerned_with: Some("356938035643809,10.0.0.7,fe80::2".parse().unwrap()),
+        mission_capes: Some(vec![String::from("hunt")]),
+    }));
+    registry.add(CyberDefenseTeam::Israel(Operation {
+        team_name: String::from("CDT Red"),
+        selectors_concerned_with: None,
+        mission_capes: None,
+    }));
+
+    registry.add(CyberDefenseTeam::Gaza(Operation {
+        team_name: String::from("CDT Blue"),
+        selectors_concerned_with: Some("356938035643809,192.168.1.1,fe80::2,10.0.0.0/24".parse().unwrap()),
+        mission_capes: None,
+    }));
+
+    println!("watching 10.0.0.7: {:?}", registry.watching(&SelectorKey::Ipv4(Ipv4Addr::new(10, 0, 0, 7))));
+    println!("watching 10.0.0.99: {:?}", registry.watching(&SelectorKey::Ipv4(Ipv4Addr::new(10, 0, 0, 99))));
+    println!("hunters: {:?}", registry.teams_with_capability("HUNT"));
+    println!("red operations: {:?}", registry.operations_for_team("CDT Red"));
+    for conflict in registry.conflicts() {
+        println!("conflict on {}: {:?} (operations {:?})", conflict.selector, conflict.teams, conflict.operations);
+    }
+    // A day of traffic written out and replayed: background noise plus a burst from 10.0.0.7 around noon
+    let day = std::env::temp_dir().join("ch6_traffic.csv");
+    let mut lines = vec![String::from("timestamp,src,imei")];
+    for timestamp in (0..86_400).step_by(97) {
+        let event = NetworkEvent::new(&timestamp.to_string(), &format!("192.168.1.{}", timestamp % 200), "").unwrap();
+        lines.push(event.to_csv());
+    }
+    for timestamp in 43_200..43_260 {
+        lines.push(format!("{},10.0.0.7,356938035643809", timestamp));
+    }
+
+    match fs::write(&day, lines.join("\n")).map_err(|e| e.to_string()).and_then(|_| read_events(&day)) {
+        Ok(events) => {
+            let messenger = StdoutMessenger;
+            let mut engine = CorrelationEngine::new(&registry, &messenger, 60, 30).unwrap();
+            match engine.replay(&events, None) {
+                Ok(matched) => println!("replayed {} events, {} matched an operation", events.len(), matched),
+                Err(e) => println!("{}", e),
+            }
+        }
+        Err(e) => println!("{}", e),
+    }
+
+    if let Some(team) = registry.remove(watch) {
+        println!("stood down {}, conflicts left: {}", team.operation().team_name, registry.conflicts().len());
+    }
+    println!("operation {} still registered: {}", watch, registry.get(watch).is_some());
+
+    // Same traffic through each limiter: 5 requests a minute per source, 20 for the suspect
+    let clock = MockClock::new(0);
+    let limit = Limit::new(5, 60).unwrap();
+    let mut limiters: Vec<(&str, Box<dyn RateLimiter<SelectorKey>>)> = vec![
+        ("fixed-window", Box::new(FixedWindow::new(limit, Box::new(clock.clone())))),
+        ("sliding-log", Box::new(SlidingLog::new(limit, Box::new(clock.clone())))),
+        ("token-bucket", Box::new(TokenBucket::new(limit, Box::new(clock.clone())))),
+    ];
+
+    for (name, limiter) in limiters.iter_mut() {
+        limiter.set_limit(SelectorKey::Imei(suspect.imei.clone()), Limit::new(20, 60).unwrap());
+
+        let start = clock.now();
+        let mut allowed = 0;
+        let mut last_denial = None;
+        for _ in 0..120 {
+            for key in suspect.keys() {
+                match limiter.check(&key) {
+                    Decision::Allow => allowed += 1,
+                    denied => last_denial = Some(denied),
+                }
+            }
+            clock.advance(1);
+        }
+
+        println!("{}: {} allowed in {}s, last answer {:?}", name, allowed, clock.now() - start, last_denial);
+    }
+
+    // And on the real clock, where the burst is cut off the same way
+    let mut live = TokenBucket::new(limit, Box::new(SystemClock::new()));
+    let allowed = (0..10).filter(|_| live.check(&SelectorKey::Ipv4(suspect.ipv4)) == Decision::Allow).count();
+    println!("token-bucket on the system clock: {} of 10 allowed", allowed);
+
+    // Eight sensors, one shared counter... lock-free vs a Mutex around the single threaded version
+    let (threads, hits_per_thread) = (8, 200_000);
+
+    let started = Instant::now();
+    let lock_free = ConcurrentHitCounter::new(300_000).unwrap();
+    bench("lock-free", threads, hits_per_thread, || lock_free.hit(tick(&started)).unwrap());
+    let lock_free_hits = lock_free.get_hits(tick(&started));
+
+    // The single threaded counter rejects hits that go back in time, so read the clock while holding the lock
+    let started = Instant::now();
+    let locked = Mutex::new(HitCounter::new(300_000).unwrap());
+    bench("mutex", threads, hits_per_thread, || {
+        let mut counter = locked.lock().unwrap();
+        counter.hit(tick(&started)).unwrap();
+    });
+    let locked_hits = locked.lock().unwrap().get_hits(tick(&started));
+
+    println!("Both counted every hit: {} vs {}", lock_free_hits, locked_hits);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    #[test]
+    fn hit_counter_keeps_one_window_of_buckets() {
+        assert!(HitCounter::new(0).is_err());
+        assert!(HitCounter::new(-5).is_err());
+
+        let mut counter = HitCounter::new(10).unwrap();
+        for timestamp in [1, 1, 2, 5, 5, 5] {
+            counter.hit(timestamp).unwrap();
+        }
+        assert_eq!(counter.buckets(), 3);
+        assert_eq!(counter.get_hits(5), 6);
+
+        // Hits can't come in out of order, and a refused one isn't counted
+        assert!(counter.hit(4).is_err());
+        assert!(counter.hit(-1).is_err());
+        assert_eq!(counter.get_hits(5), 6);
+
+        // 1 slides out when 11 comes in and 2 when 12 does, and the buckets never outnumber the window
+        counter.hit(11).unwrap();
+        assert_eq!(counter.get_hits(11), 5);
+        counter.hit(12).unwrap();
+        assert_eq!(counter.get_hits(12), 5);
+        assert_eq!(counter.buckets(), 3);
+
+        for timestamp in 12..100 {
+            counter.hit(timestamp).unwrap();
+            assert!(counter.buckets() <= 10);
+        }
+        assert_eq!(counter.get_hits(99), 10);
+        assert_eq!(counter.get_hits(200), 0);
+    }
+
+    #[test]
+    fn concurrent_hits_are_all_counted() {
+        let counter = ConcurrentHitCounter::new(300).unwrap();
+
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for timestamp in 0..300 {
+                        counter.hit(timestamp).unwrap();
+                        counter.hit(timestamp).unwrap();
+                    }
+                });
+            }
+        });
+
+        // Two hits per thread for every second of the window
+        assert_eq!(counter.get_hits(299), 300 * 2 * 8);
+
+        // Moving on a whole window reclaims the slots
+        counter.hit(600).unwrap();
+        assert_eq!(counter.get_hits(600), 1);
+        assert!(counter.hit(300).is_err());
+    }
+
+    fn limiters(clock: &MockClock) -> Vec<Box<dyn RateLimiter<&'static str>>> {
+        let limit = Limit::new(3, 10).unwrap();
+
+        vec![
+            Box::new(FixedWindow::new(limit, Box::new(clock.clone()))),
+            Box::new(SlidingLog::new(limit, Box::new(clock.clone()))),
+            Box::new(TokenBucket::new(limit, Box::new(clock.clone()))),
+        ]
+    }
+
+    #[test]
+    fn keyed_counts_slide_per_source_and_tie_in_key_order() {
+        let (a, b, c) = (
+            SelectorKey::Ipv4(Ipv4Addr::new(10, 0, 0, 1)),
+            SelectorKey::Ipv4(Ipv4Addr::new(10, 0, 0, 2)),
+            SelectorKey::Ipv4(Ipv4Addr::new(10, 0, 0, 3)),
+        );
+        let mut counter = KeyedHitCounter::new(10).unwrap();
+
+        // b goes first every second, the tie still comes out as a then b
+        for timestamp in 0..5 {
+            counter.hit(&b, timestamp).unwrap();
+            counter.hit(&a, timestamp).unwrap();
+        }
+        counter.hit(&c, 9).unwrap();
+        assert_eq!(counter.top(3, 9), vec![(a.clone(), 5), (b.clone(), 5), (c.clone(), 1)]);
+        assert_eq!(counter.top(1, 9), vec![(a.clone(), 5)]);
+
+        // Each source has its own window
+        assert_eq!(counter.get_hits(&a, 13), 1);
+        assert_eq!(counter.get_hits(&a, 14), 0);
+        assert!(counter.hit(&c, 3).is_err());
+
+        counter.prune(14);
+        assert_eq!(counter.counters.len(), 1);
+        assert_eq!(counter.top(3, 14), vec![(c, 1)]);
+    }
+
+    #[test]
+    fn space_saving_stays_within_its_error_bounds() {
+        let capacity = 4;
+        let mut table = SpaceSaving::new(capacity);
+        let mut exact: HashMap<u32, u64> = HashMap::new();
+
+        // One loud key, one medium and a long tail
+        let total = 1000;
+        for i in 0..total as u32 {
+            let key = if i % 3 == 0 { 0 } else if i % 5 == 0 { 1 } else { i % 97 };
+            table.offer(&key);
+            *exact.entry(key).or_insert(0) += 1;
+        }
+
+        assert!(table.counts.len() <= capacity);
+        for (key, &(count, error)) in &table.counts {
+            let truth = exact[key];
+            assert!(count - error <= truth && truth <= count, "{}: {} not in {}..={}", key, truth, count - error, count);
+            assert!(error <= total / capacity as u64);
+        }
+
+        // Anything over total / capacity can't have been pushed out
+        for (key, &truth) in &exact {
+            if truth > total / capacity as u64 {
+                assert!(table.counts.contains_key(key), "{} was dropped with {} hits", key, truth);
+            }
+        }
+    }
+
+    #[test]
+    fn heavy_hitters_only_look_at_the_last_window() {
+        let mut heavy = HeavyHitters::new(10, 2).unwrap();
+
+        // a is busy for the first ten seconds, b for the five after
+        for timestamp in 0..10 {
+            for _ in 0..3 {
+                heavy.hit(&"a", timestamp).unwrap();
+            }
+        }
+        assert_eq!(heavy.top(2, 9), vec![("a", 30, 30)]);
+
+        for timestamp in 10..15 {
+            heavy.hit(&"b", timestamp).unwrap();
+        }
+        assert_eq!(heavy.top(2, 14), vec![("a", 15, 15), ("b", 5, 5)]);
+        assert_eq!(heavy.top(2, 19), vec![("b", 5, 5)]);
+        assert_eq!(heavy.seconds.len(), 10);
+        assert!(heavy.hit(&"a", 3).is_err());
+
+        // Three sources into two slots a second: x never leaves, y and z fight over the other one
+        let mut heavy = HeavyHitters::new(10, 2).unwrap();
+        for timestamp in 20..30 {
+            for key in ["x", "x", "x", "y", "z"] {
+                heavy.hit(&key, timestamp).unwrap();
+            }
+        }
+
+        assert_eq!(heavy.top(1, 29), vec![("x", 30, 30)]);
+        for (key, estimate, guaranteed) in heavy.top(3, 29) {
+            let truth = if key == "x" { 30 } else { 10 };
+            assert!(guaranteed <= truth && truth <= estimate, "{}: {} not in {}..={}", key, truth, guaranteed, estimate);
+        }
+
+        // Ties come out in key order too
+        let mut heavy = HeavyHitters::new(10, 4).unwrap();
+        for key in ["d", "c", "d", "c"] {
+            heavy.hit(&key, 0).unwrap();
+        }
+        assert_eq!(heavy.top(2, 0), vec![("c", 2, 2), ("d", 2, 2)]);
+    }
+
+    #[test]
+    fn every_limiter_stops_a_burst_and_says_when_to_retry() {
+        let clock = MockClock::new(100);
+
+        for mut limiter in limiters(&clock) {
+            for _ in 0..3 {
+                assert_eq!(limiter.check(&"sensor"), Decision::Allow);
+            }
+
+            match limiter.check(&"sensor") {
+                Decision::Deny { retry_after } => {
+                    assert!(retry_after > 0 && retry_after <= 10);
+
+                    // Another source isn't affected
+                    assert_eq!(limiter.check(&"other"), Decision::Allow);
+
+                    clock.advance(retry_after);
+                    assert_eq!(limiter.check(&"sensor"), Decision::Allow);
+                    clock.advance(-retry_after);
+                }
+                Decision::Allow => panic!("the fourth request should be denied"),
+            }
+        }
+    }
+
+    #[test]
+    fn sliding_log_retries_once_the_oldest_hit_slides_out() {
+        let clock = MockClock::new(0);
+        let mut limiter = SlidingLog::new(Limit::new(2, 10).unwrap(), Box::new(clock.clone()));
+
+        assert_eq!(limiter.check(&"sensor"), Decision::Allow);
+        clock.advance(4);
+        assert_eq!(limiter.check(&"sensor"), Decision::Allow);
+        assert_eq!(limiter.check(&"sensor"), Decision::Deny { retry_after: 6 });
+
+        clock.advance(6);
+        assert_eq!(limiter.check(&"sensor"), Decision::Allow);
+        assert_eq!(limiter.check(&"sensor"), Decision::Deny { retry_after: 4 });
+    }
+
+    #[test]
+    fn per_key_limits_override_the_default() {
+        let clock = MockClock::new(0);
+        let mut limiter = TokenBucket::new(Limit::new(1, 10).unwrap(), Box::new(clock.clone()));
+        limiter.set_limit("gateway", Limit::new(4, 10).unwrap());
+
+        let allowed = (0..5).filter(|_| limiter.check(&"gateway") == Decision::Allow).count();
+        assert_eq!(allowed, 4);
+        assert_eq!(limiter.check(&"sensor"), Decision::Allow);
+        assert_eq!(limiter.check(&"sensor"), Decision::Deny { retry_after: 10 });
+    }
+
+    #[test]
+    fn idle_keys_are_forgotten() {
+        let clock = MockClock::new(0);
+        let limit = Limit::new(2, 10).unwrap();
+        let mut fixed = FixedWindow::new(limit, Box::new(clock.clone()));
+        let mut sliding = SlidingLog::new(limit, Box::new(clock.clone()));
+        let mut bucket = TokenBucket::new(limit, Box::new(clock.clone()));
+
+        // A scan touches a hundred sources once each
+        for source in 0..100 {
+            let key = format!("10.0.0.{}", source);
+            assert_eq!(fixed.check(&key), Decision::Allow);
+            assert_eq!(sliding.check(&key), Decision::Allow);
+            assert_eq!(bucket.check(&key), Decision::Allow);
+        }
+        assert_eq!((fixed.windows.len(), sliding.logs.len(), bucket.buckets.len()), (100, 100, 100));
+
+        // Still inside the window, nothing goes yet
+        clock.advance(4);
+        let sensor = String::from("sensor");
+        fixed.check(&sensor);
+        sliding.check(&sensor);
+        bucket.check(&sensor);
+        assert_eq!((fixed.windows.len(), sliding.logs.len(), bucket.buckets.len()), (101, 101, 101));
+
+        clock.advance(6);
+        fixed.check(&sensor);
+        sliding.check(&sensor);
+        bucket.check(&sensor);
+        assert_eq!((fixed.windows.len(), sliding.logs.len(), bucket.buckets.len()), (1, 1, 1));
+    }
+
+    #[test]
+    fn token_buckets_refill_in_whole_numbers() {
+        let clock = MockClock::new(0);
+        let mut limiter = TokenBucket::new(Limit::new(3, 10).unwrap(), Box::new(clock.clone()));
+
+        for _ in 0..3 {
+            assert_eq!(limiter.check(&"sensor"), Decision::Allow);
+        }
+        // One request refills every 10 / 3 seconds, so the next is 4 seconds away
+        assert_eq!(limiter.check(&"sensor"), Decision::Deny { retry_after: 4 });
+        clock.advance(3);
+        assert_eq!(limiter.check(&"sensor"), Decision::Deny { retry_after: 1 });
+        clock.advance(1);
+        assert_eq!(limiter.check(&"sensor"), Decision::Allow);
+
+        // Limits that can't be counted are turned away up front
+        assert!(Limit::new(i32::MAX, 2).is_err());
+        assert!(Limit::new(1000, 86_400).is_ok());
+    }
+
+    #[test]
+    fn selectors_are_validated_field_by_field() {
+        assert!(SelectorAttributes::new("490154203237518", "10.0.0.7", "fe80::1").is_ok());
+
+        assert_eq!("490154203237519".parse::<Imei>(), Err(SelectorError::ImeiChecksum(String::from("490154203237519"))));
+        assert_eq!("4901542032375".parse::<Imei>(), Err(SelectorError::ImeiLength(String::from("4901542032375"))));
+        assert_eq!("49015420323751x".parse::<Imei>(), Err(SelectorError::ImeiNotNumeric(String::from("49015420323751x"))));
+        assert!(matches!(SelectorAttributes::new("490154203237518", "10.0.0", "fe80::1"), Err(SelectorError::InvalidIpv4(_))));
+        assert!(matches!(SelectorAttributes::new("490154203237518", "10.0.0.7", "fe80:::1"), Err(SelectorError::InvalidIpv6(_))));
+        assert!(matches!("490154203237518,10.0.0.7".parse::<SelectorAttributes>(), Err(SelectorError::MissingField("ipv6"))));
+
+        // Anything after the three fields has to be a subnet
+        let selector: SelectorAttributes = "490154203237518,10.0.0.7,fe80::1,10.0.0.0/24, fe80::/10".parse().unwrap();
+        assert_eq!(selector.subnets, vec!["10.0.0.0/24".parse().unwrap(), "fe80::/10".parse().unwrap()]);
+        assert_eq!(
+            "490154203237518,10.0.0.7,fe80::1,ops-team".parse::<SelectorAttributes>().err(),
+            Some(SelectorError::UnexpectedField(String::from("ops-team")))
+        );
+        assert_eq!(
+            "490154203237518,10.0.0.7,fe80::1,".parse::<SelectorAttributes>().err(),
+            Some(SelectorError::UnexpectedField(String::new()))
+        );
+    }
+
+    #[test]
+    fn cidr_matches_whole_subnets() {
+        let private: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(private.contains(&"10.200.3.4".parse().unwrap()));
+        assert!(!private.contains(&"11.0.0.1".parse().unwrap()));
+        assert!(!private.contains(&"fe80::1".parse().unwrap()));
+
+        let everything: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains(&"8.8.8.8".parse().unwrap()));
+
+        let link_local: Cidr = "fe80::/10".parse().unwrap();
+        assert!(link_local.contains(&"febf::1".parse().unwrap()));
+        assert!(!link_local.contains(&"fec0::1".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0.0".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn registry_indexes_follow_adds_and_removes() {
+        let mut registry = MissionRegistry::new();
+        let operation = |team: &str, selectors: &str, capes: &[&str]| Operation {
+            team_name: team.to_string(),
+            selectors_concerned_with: Some(selectors.parse().unwrap()),
+            mission_capes: Some(capes.iter().map(|cape| cape.to_string()).collect()),
+        };
+
+        let red = registry.add(CyberDefenseTeam::Israel(operation("red", "490154203237518,10.0.0.7,fe80::1", &["hunt"])));
+        let also_red = registry.add(CyberDefenseTeam::Israel(operation("red", "490154203237518,10.0.0.8,fe80::3", &["forensics"])));
+        let imei = SelectorKey::Imei("490154203237518".parse().unwrap());
+
+        // Two operations from the same team on one IMEI isn't a conflict
+        assert_eq!(registry.watching(&imei), vec![red, also_red]);
+        assert!(registry.conflicts().is_empty());
+
+        let blue = registry.add(CyberDefenseTeam::Gaza(operation("blue", "356938035643809,10.0.0.7,fe80::2", &["Hunt"])));
+        assert_eq!(registry.teams_with_capability("hunt"), vec!["blue", "red"]);
+        assert_eq!(registry.conflicts().len(), 1);
+        assert_eq!(registry.conflicts()[0].selector, SelectorKey::Ipv4(Ipv4Addr::new(10, 0, 0, 7)));
+
+        registry.remove(red);
+        assert_eq!(registry.watching(&imei), vec![also_red]);
+        assert_eq!(registry.teams_with_capability("hunt"), vec!["blue"]);
+        assert!(registry.conflicts().is_empty());
+        assert_eq!(registry.operations_for_team("blue"), vec![blue]);
+        assert!(registry.remove(red).is_none());
+    }
+
+    #[test]
+    fn subnet_selectors_match_every_address_inside() {
+        let mut registry = MissionRegistry::new();
+        let operation = |team: &str, selectors: &str| Operation {
+            team_name: team.to_string(),
+            selectors_concerned_with: Some(selectors.parse().unwrap()),
+            mission_capes: None,
+        };
+
+        let red = registry.add(CyberDefenseTeam::Israel(operation("red", "490154203237518,10.0.0.7,fe80::1,10.0.0.0/24")));
+        let blue = registry.add(CyberDefenseTeam::Gaza(operation("blue", "356938035643809,192.168.0.1,fe80::2,10.0.0.0/8")));
+        let inside = |addr: &str| SelectorKey::Ipv4(addr.parse().unwrap());
+
+        assert_eq!(registry.watching(&inside("10.0.0.7")), vec![red, blue]);
+        assert_eq!(registry.watching(&inside("10.0.0.200")), vec![red, blue]);
+        assert_eq!(registry.watching(&inside("10.9.0.1")), vec![blue]);
+        assert!(registry.watching(&inside("11.0.0.1")).is_empty());
+        assert_eq!(registry.watching(&SelectorKey::Subnet("10.0.0.0/8".parse().unwrap())), vec![blue]);
+
+        // Host bits don't make it a different subnet
+        assert_eq!("10.0.0.7/24".parse::<Cidr>().unwrap(), "10.0.0.0/24".parse::<Cidr>().unwrap());
+        assert_eq!("10.0.0.7/24".parse::<Cidr>().unwrap().to_string(), "10.0.0.0/24");
+        assert_eq!("fe80::1:2/112".parse::<Cidr>().unwrap().to_string(), "fe80::1:0/112");
+
+        // The same subnet from two teams is a conflict like any other selector, however it's written
+        let green = registry.add(CyberDefenseTeam::Gaza(operation("green", "356938035643809,192.168.0.2,fe80::3,10.0.0.7/24")));
+        let subnet = SelectorKey::Subnet("10.0.0.0/24".parse().unwrap());
+        assert!(registry.conflicts().iter().any(|conflict| conflict.selector == subnet && conflict.operations == vec![red, green]));
+
+        registry.remove(blue);
+        registry.remove(green);
+        assert_eq!(registry.watching(&inside("10.9.0.1")), Vec::<OperationId>::new());
+        assert!(registry.by_subnet.values().all(|ids| ids == &BTreeSet::from([red])));
+
+        // The correlation engine picks the subnet up too
+        let messenger = MockMessenger { sent_messages: RefCell::new(vec![]) };
+        let mut engine = CorrelationEngine::new(&registry, &messenger, 10, 2).unwrap();
+        assert_eq!(engine.ingest(&NetworkEvent::from_csv("1,10.0.0.42,").unwrap()).unwrap(), vec![red]);
+        assert_eq!(engine.ingest(&NetworkEvent::from_csv("2,10.0.0.43,").unwrap()).unwrap(), vec![red]);
+        assert_eq!(messenger.sent_messages.borrow().len(), 1);
+    }
+
+    struct MockMessenger {
+        sent_messages: RefCell<Vec<String>>,
+    }
+
+    impl Messenger for MockMessenger {
+        fn send(&self, message: &str) {
+            self.sent_messages.borrow_mut().push(String::from(message));
+        }
+    }
+
+    #[test]
+    fn event_files_read_back_sorted_and_point_at_bad_lines() {
+        let dir = std::env::temp_dir().join(format!("ch6-events-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let events: Vec<NetworkEvent> = ["5,10.0.0.7,490154203237518", "1,fe80::1,", "3,192.168.0.1"]
+            .iter()
+            .map(|line| NetworkEvent::from_csv(line).unwrap())
+            .collect();
+        let mut sorted = events.clone();
+        sorted.sort_by_key(|event| event.timestamp);
+
+        // Written out of order, with a header and without
+        let lines: Vec<String> = events.iter().map(NetworkEvent::to_csv).collect();
+        let with_header = dir.join("with_header.csv");
+        let without_header = dir.join("without_header.csv");
+        fs::write(&with_header, format!("timestamp,src,imei\n{}\n", lines.join("\n"))).unwrap();
+        fs::write(&without_header, lines.join("\n")).unwrap();
+        assert_eq!(read_events(&with_header).unwrap(), sorted);
+        assert_eq!(read_events(&without_header).unwrap(), sorted);
+
+        let ndjson = dir.join("events.ndjson");
+        fs::write(
+            &ndjson,
+            "{\"timestamp\": 5, \"src\": \"10.0.0.7\", \"imei\": \"490154203237518\"}\n\n{\"timestamp\": 1, \"src\": \"fe80::1\"}\n{\"src\": \"192.168.0.1\", \"timestamp\": 3}\n",
+        )
+        .unwrap();
+        assert_eq!(read_events(&ndjson).unwrap(), sorted);
+
+        // A bad line is reported by file and line number
+        fs::write(&with_header, "timestamp,src,imei\n1,10.0.0.7,\nsoon,10.0.0.7,\n").unwrap();
+        assert_eq!(read_events(&with_header).unwrap_err(), format!("{} line 3: soon isn't a timestamp", with_header.display()));
+        fs::write(&ndjson, "{\"timestamp\": 1}\n").unwrap();
+        assert_eq!(read_events(&ndjson).unwrap_err(), format!("{} line 1: missing src", ndjson.display()));
+        assert!(read_events(&dir.join("missing.csv")).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replays_keep_the_gaps_between_events_scaled_down() {
+        let mut registry = MissionRegistry::new();
+        registry.add(CyberDefenseTeam::Israel(Operation {
+            team_name: String::from("red"),
+            selectors_concerned_with: Some("490154203237518,10.0.0.7,fe80::1".parse().unwrap()),
+            mission_capes: None,
+        }));
+        let messenger = MockMessenger { sent_messages: RefCell::new(vec![]) };
+        let mut engine = CorrelationEngine::new(&registry, &messenger, 10, 3).unwrap();
+
+        // 4 seconds of traffic at 100x is 40ms
+        let events: Vec<NetworkEvent> = [0, 2, 4].iter().map(|t| NetworkEvent::from_csv(&format!("{},10.0.0.7,", t)).unwrap()).collect();
+        let started = Instant::now();
+        assert_eq!(engine.replay(&events, Some(100.0)).unwrap(), 3);
+        assert!(started.elapsed() >= Duration::from_millis(40));
+        assert_eq!(messenger.sent_messages.borrow().len(), 1);
+    }
+
+    #[test]
+    fn bursts_on_watched_selectors_alert_once() {
+        let mut registry = MissionRegistry::new();
+        let id = registry.add(CyberDefenseTeam::Israel(Operation {
+            team_name: String::from("red"),
+            selectors_concerned_with: Some("490154203237518,10.0.0.7,fe80::1".parse().unwrap()),
+            mission_capes: None,
+        }));
+
+        let messenger = MockMessenger { sent_messages: RefCell::new(vec![]) };
+        let mut engine = CorrelationEngine::new(&registry, &messenger, 10, 3).unwrap();
+
+        // Unwatched traffic never matches
+        assert!(engine.ingest(&NetworkEvent::from_csv("1,192.168.0.1,").unwrap()).unwrap().is_empty());
+
+        // Matching on the IMEI alone counts too
+        let events: Vec<NetworkEvent> = [
+            r#"{"timestamp": 2, "src": "10.0.0.7"}"#,
+            r#"{"timestamp": 3, "src": "172.16.0.9", "imei": "490154203237518"}"#,
+            r#"{"timestamp": 4, "src": "10.0.0.7"}"#,
+            r#"{"timestamp": 5, "src": "10.0.0.7"}"#,
+        ]
+        .iter()
+        .map(|line| NetworkEvent::from_json(line).unwrap())
+        .collect();
+
+        assert_eq!(engine.replay(&events, None).unwrap(), 4);
+        assert_eq!(messenger.sent_messages.borrow().len(), 1);
+
+        // Once the burst slides out of the window the next one alerts again
+        for timestamp in 30..33 {
+            assert_eq!(engine.ingest(&NetworkEvent::from_csv(&format!("{},10.0.0.7,", timestamp)).unwrap()).unwrap(), vec![id]);
+        }
+        assert_eq!(messenger.sent_messages.borrow().len(), 2);
+    }
+}
@@ -1,48 +1,1648 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::AtomicI32;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-struct Cashier {
-    n: i32,
-    length: i32,
-    discount: i32,
-    items: HashMap<i32, i32>,
+// Cents, so 0.1 + 0.2 is exactly 0.3 and every bill comes out the same to the cent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+struct Money(i64);
+
+impl Money {
+    fn from_cents(cents: i64) -> Self {
+        Money(cents)
+    }
+
+    fn cents(&self) -> i64 {
+        self.0
+    }
+
+    // None when it won't fit in an i64, the callers turn that into an error instead of a wrapped bill
+    fn times(&self, quantity: i64) -> Option<Money> {
+        self.0.checked_mul(quantity).map(Money)
+    }
+
+    fn checked_add(&self, other: Money) -> Option<Money> {
+        self.0.checked_add(other.0).map(Money)
+    }
+
+    // percent off, rounded half up to the nearest cent... worked out in i128 so a big amount can't overflow on the way
+    fn percent_off(&self, percent: i64) -> Option<Money> {
+        let cents = (self.0 as i128 * (100 - percent as i128) + 50).div_euclid(100);
+        i64::try_from(cents).ok().map(Money)
+    }
+
+    // basis points (1/100th of a percent) of this amount, rounded half up... 825 is 8.25%
+    fn basis_points(&self, bp: i64) -> Option<Money> {
+        let cents = (self.0 as i128 * bp as i128 + 5_000).div_euclid(10_000);
+        i64::try_from(cents).ok().map(Money)
+    }
+
+    // 12.34 with no currency sign, for JSON and CSV
+    fn to_decimal(self) -> String {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        format!("{}{}.{:02}", sign, self.0.abs() / 100, self.0.abs() % 100)
+    }
 }
 
+impl std::ops::Add for Money {
+    type Output = Money;
 
-impl Cashier {
+    fn add(self, other: Money) -> Money {
+        Money(self.0 + other.0)
+    }
+}
+
+impl std::iter::Sum for Money {
+    fn sum<I: Iterator<Item = Money>>(iter: I) -> Money {
+        iter.fold(Money(0), |total, money| total + money)
+    }
+}
+
+impl std::ops::Sub for Money {
+    type Output = Money;
+
+    fn sub(self, other: Money) -> Money {
+        Money(self.0 - other.0)
+    }
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        write!(f, "{}${}.{:02}", sign, self.0.abs() / 100, self.0.abs() % 100)
+    }
+}
+
+// "12", "12.5" or "12.50", never through a float
+impl FromStr for Money {
+    type Err = CashierError;
 
-    fn new(n: i32, discount: i32, products: Vec<i32>, prices: Vec<i32>) -> Self {
-        let mut mapper: HashMap<i32, i32> = HashMap::new();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || CashierError::InvalidPrice(s.to_string());
+        let trimmed = s.trim().trim_start_matches('$');
+        let (units, fraction) = trimmed.split_once('.').unwrap_or((trimmed, ""));
 
+        if units.is_empty() || fraction.len() > 2 || !units.chars().chain(fraction.chars()).all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+
+        let units: i64 = units.parse().map_err(|_| invalid())?;
+        let fraction: i64 = format!("{:0<2}", fraction).parse().map_err(|_| invalid())?;
+        let cents = units.checked_mul(100).and_then(|cents| cents.checked_add(fraction)).ok_or_else(invalid)?;
+
+        Ok(Money(cents))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum CashierError {
+    UnknownProduct(String),
+    DuplicateSku(String),
+    InvalidPrice(String),
+    InvalidQuantity(String, i32),
+    InvalidPromotion(String),
+    MismatchedLines(usize, usize),
+    OutOfStock(String, i64, i64),
+    TooManyCustomers,
+    BadRestock(usize, String),
+    Io(String),
+}
+
+impl fmt::Display for CashierError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CashierError::UnknownProduct(sku) => write!(f, "{} isn't in the catalog", sku),
+            CashierError::DuplicateSku(sku) => write!(f, "{} is already in the catalog", sku),
+            CashierError::InvalidPrice(price) => write!(f, "{} isn't a price", price),
+            CashierError::InvalidQuantity(sku, quantity) => write!(f, "can't sell {} of {}", quantity, sku),
+            CashierError::InvalidPromotion(promotion) => write!(f, "{} doesn't make sense as a promotion", promotion),
+            CashierError::MismatchedLines(products, amounts) => write!(f, "{} products but {} amounts", products, amounts),
+            CashierError::OutOfStock(sku, wanted, left) => write!(f, "wanted {} of {} but only {} left", wanted, sku, left),
+            CashierError::TooManyCustomers => write!(f, "this till has run out of customer numbers"),
+            CashierError::BadRestock(line, text) => write!(f, "restock line {} doesn't make sense: {}", line, text),
+            CashierError::Io(e) => write!(f, "couldn't read the restock file: {}", e),
+        }
+    }
+}
+
+impl Error for CashierError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Product {
+    sku: String,
+    name: String,
+    category: String,
+    price: Money,
+}
+
+struct Catalog {
+    products: HashMap<String, Product>,
+}
+
+impl Catalog {
+
+    fn new() -> Self {
+        Self {
+            products: HashMap::new(),
+        }
+    }
+
+    // The Leetcode 1357 input: ids become SKUs, prices are whole dollars
+    fn from_ids(products: Vec<i32>, prices: Vec<i32>) -> Result<Self, CashierError> {
+        if products.len() != prices.len() {
+            return Err(CashierError::MismatchedLines(products.len(), prices.len()));
+        }
+
+        let mut catalog = Self::new();
         products
             .iter()
             .zip(prices.iter())
-            .for_each(|(&p, &pr)| { mapper.entry(p).or_insert(pr); });
+            .try_for_each(|(&p, &pr)| catalog.add(&p.to_string(), &format!("Product {}", p), "general", Money::from_cents(pr as i64 * 100)))?;
+
+        Ok(catalog)
+    }
+
+    fn add(&mut self, sku: &str, name: &str, category: &str, price: Money) -> Result<(), CashierError> {
+        if price.cents() < 0 {
+            return Err(CashierError::InvalidPrice(price.to_string()));
+        }
+        if self.products.contains_key(sku) {
+            return Err(CashierError::DuplicateSku(sku.to_string()));
+        }
+
+        self.products.insert(
+            sku.to_string(),
+            Product { sku: sku.to_string(), name: name.to_string(), category: category.to_string(), price },
+        );
+        Ok(())
+    }
+
+    fn get(&self, sku: &str) -> Result<&Product, CashierError> {
+        self.products.get(sku).ok_or(CashierError::UnknownProduct(sku.to_string()))
+    }
+
+    // Hands back the old price
+    fn set_price(&mut self, sku: &str, price: Money) -> Result<Money, CashierError> {
+        if price.cents() < 0 {
+            return Err(CashierError::InvalidPrice(price.to_string()));
+        }
+        let product = self.products.get_mut(sku).ok_or(CashierError::UnknownProduct(sku.to_string()))?;
+
+        Ok(std::mem::replace(&mut product.price, price))
+    }
+}
+
+// Where the till gets the time of day from, seconds since the UNIX epoch
+// Send + Sync so one till setup can be shared by every register
+trait Clock: Send + Sync {
+    fn now(&self) -> u64;
+}
+
+struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+// Always the same moment, for demos and tests
+struct FixedClock(u64);
+
+impl Clock for FixedClock {
+    fn now(&self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Line {
+    sku: String,
+    name: String,
+    category: String,
+    quantity: i64,
+    unit_price: Money,
+    total: Money,     // unit_price x quantity, checked when the line is rung up
+    backordered: i64, // part of the quantity that wasn't on the shelf, sent when the next restock comes in
+}
+
+// What a promotion gets to look at
+struct Checkout<'a> {
+    lines: &'a [Line],
+    customer: i32,    // 1 for the first customer this till has served
+    hour: u32,        // hour of the day (UTC), 0-23
+    running_total: Money, // after the promotions applied so far
+}
+
+/*
+    - Promotions are trait objects so new ones can be plugged in without touching Cashier
+    - Run from highest priority to lowest, each one sees the total left by the ones before it
+    - An exclusive promotion only applies if nothing else has yet, and once it does nothing after it can
+    - Priority and exclusivity are set on each promotion when it's made, two coupons of the same kind can differ
+*/
+trait Promotion: Send + Sync {
+    fn name(&self) -> String;
+    fn priority(&self) -> i32;
+    fn exclusive(&self) -> bool;
+    // The SKU a promotion is tied to, if any, so tax comes off the right category
+    fn sku(&self) -> Option<&str> {
+        None
+    }
+    // How much comes off, None if it doesn't apply to this checkout
+    fn discount(&self, checkout: &Checkout) -> Option<Money>;
+}
+
+// The original rule: every nth customer gets a percentage off the whole bill
+struct EveryNthCustomer {
+    n: i32,
+    percent: i64,
+    priority: i32,
+    exclusive: bool,
+}
+
+impl EveryNthCustomer {
+    fn new(n: i32, percent: i64, priority: i32, exclusive: bool) -> Result<Self, CashierError> {
+        if !(0..=100).contains(&percent) {
+            return Err(CashierError::InvalidPromotion(format!("{}% off every {} customers", percent, n)));
+        }
+
+        Ok(Self { n, percent, priority, exclusive })
+    }
+}
+
+impl Promotion for EveryNthCustomer {
+    fn name(&self) -> String {
+        let suffix = match (self.n % 10, self.n % 100) {
+            (_, 11..=13) => "th",
+            (1, _) => "st",
+            (2, _) => "nd",
+            (3, _) => "rd",
+            _ => "th",
+        };
+        format!("Every {}{} customer {}% off", self.n, suffix, self.percent)
+    }
+
+    fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    fn exclusive(&self) -> bool {
+        self.exclusive
+    }
+
+    fn discount(&self, checkout: &Checkout) -> Option<Money> {
+        if self.n > 0 && checkout.customer % self.n == 0 {
+            Some(checkout.running_total - checkout.running_total.percent_off(self.percent)?)
+        } else {
+            None
+        }
+    }
+}
+
+// Buy `buy` of a SKU and get the next `free` of it free, repeating
+struct BuyXGetY {
+    sku: String,
+    buy: i64,
+    free: i64,
+    priority: i32,
+    exclusive: bool,
+}
+
+impl BuyXGetY {
+    // Both have to be at least 1, anything else is a free shelf or nothing at all
+    fn new(sku: &str, buy: i64, free: i64, priority: i32, exclusive: bool) -> Result<Self, CashierError> {
+        if buy <= 0 || free <= 0 || buy.checked_add(free).is_none() {
+            return Err(CashierError::InvalidPromotion(format!("buy {} get {} free on {}", buy, free, sku)));
+        }
+
+        Ok(Self { sku: sku.to_string(), buy, free, priority, exclusive })
+    }
+}
+
+impl Promotion for BuyXGetY {
+    fn sku(&self) -> Option<&str> {
+        Some(&self.sku)
+    }
+
+    fn name(&self) -> String {
+        format!("Buy {} get {} free on {}", self.buy, self.free, self.sku)
+    }
+
+    fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    fn exclusive(&self) -> bool {
+        self.exclusive
+    }
 
+    fn discount(&self, checkout: &Checkout) -> Option<Money> {
+        let line = checkout.lines.iter().find(|line| line.sku == self.sku)?;
+        let free_units = line.quantity.checked_div(self.buy + self.free)? * self.free;
+
+        if free_units > 0 {
+            line.unit_price.times(free_units)
+        } else {
+            None
+        }
+    }
+}
+
+struct SkuPercentOff {
+    sku: String,
+    percent: i64,
+    priority: i32,
+    exclusive: bool,
+}
+
+impl SkuPercentOff {
+    fn new(sku: &str, percent: i64, priority: i32, exclusive: bool) -> Result<Self, CashierError> {
+        if !(0..=100).contains(&percent) {
+            return Err(CashierError::InvalidPromotion(format!("{}% off {}", percent, sku)));
+        }
+
+        Ok(Self { sku: sku.to_string(), percent, priority, exclusive })
+    }
+}
+
+impl Promotion for SkuPercentOff {
+    fn sku(&self) -> Option<&str> {
+        Some(&self.sku)
+    }
+
+    fn name(&self) -> String {
+        format!("{}% off {}", self.percent, self.sku)
+    }
+
+    fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    fn exclusive(&self) -> bool {
+        self.exclusive
+    }
+
+    fn discount(&self, checkout: &Checkout) -> Option<Money> {
+        let line = checkout.lines.iter().find(|line| line.sku == self.sku)?;
+        Some(line.total - line.total.percent_off(self.percent)?)
+    }
+}
+
+// Spend at least `minimum` and get `off` taken off
+struct ThresholdCoupon {
+    minimum: Money,
+    off: Money,
+    priority: i32,
+    exclusive: bool,
+}
+
+impl Promotion for ThresholdCoupon {
+    fn name(&self) -> String {
+        format!("{} off when you spend {}", self.off, self.minimum)
+    }
+
+    fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    fn exclusive(&self) -> bool {
+        self.exclusive
+    }
+
+    fn discount(&self, checkout: &Checkout) -> Option<Money> {
+        if checkout.running_total >= self.minimum {
+            Some(self.off)
+        } else {
+            None
+        }
+    }
+}
+
+// Happy hour: a percentage off everything between two hours of the day (end not included, can wrap past midnight)
+struct TimeOfDay {
+    start_hour: u32,
+    end_hour: u32,
+    percent: i64,
+    priority: i32,
+    exclusive: bool,
+}
+
+impl TimeOfDay {
+    // Hours are 0-23, midnight is 0 not 24
+    fn new(start_hour: u32, end_hour: u32, percent: i64, priority: i32, exclusive: bool) -> Result<Self, CashierError> {
+        if !(0..=100).contains(&percent) || start_hour > 23 || end_hour > 23 {
+            return Err(CashierError::InvalidPromotion(format!("{:02}:00-{:02}:00 {}% off", start_hour, end_hour, percent)));
+        }
+
+        Ok(Self { start_hour, end_hour, percent, priority, exclusive })
+    }
+}
+
+impl Promotion for TimeOfDay {
+    fn name(&self) -> String {
+        format!("{:02}:00-{:02}:00 {}% off", self.start_hour, self.end_hour, self.percent)
+    }
+
+    fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    fn exclusive(&self) -> bool {
+        self.exclusive
+    }
+
+    fn discount(&self, checkout: &Checkout) -> Option<Money> {
+        let inside = if self.start_hour <= self.end_hour {
+            checkout.hour >= self.start_hour && checkout.hour < self.end_hour
+        } else {
+            checkout.hour >= self.start_hour || checkout.hour < self.end_hour
+        };
+
+        if inside {
+            Some(checkout.running_total - checkout.running_total.percent_off(self.percent)?)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct AppliedPromotion {
+    name: String,
+    sku: Option<String>,
+    amount: Money,
+}
+
+// Sales tax per category in basis points, anything unlisted pays the default
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+struct TaxRates {
+    default: i64,
+    by_category: HashMap<String, i64>,
+}
+
+impl TaxRates {
+    fn new(default: i64) -> Self {
         Self {
-            n,
-            length: 0,
-            discount,
-            items: mapper,
+            default,
+            by_category: HashMap::new(),
         }
     }
-    
-    fn apply_discount(&self, bill: f64) -> f64 {
-        bill * ((100 - self.discount) as f64 / 100.0)
+
+    fn set(&mut self, category: &str, basis_points: i64) {
+        self.by_category.insert(category.to_string(), basis_points);
+    }
+
+    fn rate(&self, category: &str) -> i64 {
+        self.by_category.get(category).copied().unwrap_or(self.default)
     }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TaxLine {
+    category: String,
+    basis_points: i64,
+    taxable: Money, // the category's share of the bill after promotions
+    tax: Money,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Receipt {
+    customer: i32,
+    lines: Vec<Line>,
+    subtotal: Money,
+    promotions: Vec<AppliedPromotion>,
+    discounts: Money,
+    taxes: Vec<TaxLine>,
+    total: Money,
+}
+
+impl Receipt {
+    // SKU promotions come off their own category, the rest is spread over the categories in proportion to what's
+    // left in each (cents left over go to the biggest remainders)... so every category is taxed on what was paid for it.
+    // The subtotal was checked when the lines were rung up, only the tax can still push it past what fits
+    fn new(customer: i32, lines: Vec<Line>, promotions: Vec<AppliedPromotion>, rates: &TaxRates) -> Result<Self, CashierError> {
+        let subtotal: Money = lines.iter().map(|line| line.total).sum();
+        let too_big = || CashierError::InvalidPrice(format!("{} with tax", subtotal));
+        let discounts: Money = promotions.iter().map(|promotion| promotion.amount).sum();
+
+        let mut spent: BTreeMap<&str, i64> = BTreeMap::new();
+        for line in &lines {
+            *spent.entry(line.category.as_str()).or_insert(0) += line.total.cents();
+        }
+
+        let mut targeted: BTreeMap<&str, i64> = BTreeMap::new();
+        let mut spread = 0;
+        for promotion in &promotions {
+            let category = promotion
+                .sku
+                .as_ref()
+                .and_then(|sku| lines.iter().find(|line| &line.sku == sku))
+                .map(|line| line.category.as_str());
+
+            match category {
+                Some(category) => *targeted.entry(category).or_insert(0) += promotion.amount.cents(),
+                None => spread += promotion.amount.cents(),
+            }
+        }
+
+        let remaining: BTreeMap<&str, i64> = spent
+            .iter()
+            .map(|(&category, &cents)| (category, cents - targeted.get(category).copied().unwrap_or(0)))
+            .collect();
+        let remaining_total: i64 = remaining.values().sum();
+
+        let mut shares: Vec<(&str, i64, i64)> = remaining
+            .iter()
+            .map(|(&category, &cents)| {
+                let scaled = spread as i128 * cents as i128;
+                let share = if remaining_total == 0 { 0 } else { (scaled / remaining_total as i128) as i64 };
+                let remainder = if remaining_total == 0 { 0 } else { (scaled % remaining_total as i128) as i64 };
+                (category, share, remainder)
+            })
+            .collect();
+
+        let mut left_over = spread - shares.iter().map(|&(_, share, _)| share).sum::<i64>();
+        let mut by_remainder: Vec<usize> = (0..shares.len()).collect();
+        by_remainder.sort_by_key(|&i| -shares[i].2);
+        for i in by_remainder {
+            if left_over == 0 {
+                break;
+            }
+            shares[i].1 += 1;
+            left_over -= 1;
+        }
 
-    fn get_bill(&mut self, product: Vec<i32>, amount: Vec<i32>) -> f64 {
-        self.length += 1;
+        let taxes: Vec<TaxLine> = shares
+            .iter()
+            .map(|&(category, share, _)| {
+                let taxable = Money(remaining[category] - share);
+                let basis_points = rates.rate(category);
+                Ok(TaxLine {
+                    category: category.to_string(),
+                    basis_points,
+                    taxable,
+                    tax: taxable.basis_points(basis_points).ok_or_else(too_big)?,
+                })
+            })
+            .collect::<Result<Vec<TaxLine>, CashierError>>()?;
 
-        let result: f64 = product
+        let total = taxes
+            .iter()
+            .try_fold(subtotal - discounts, |total, tax| total.checked_add(tax.tax))
+            .ok_or_else(too_big)?;
+
+        Ok(Self { customer, lines, subtotal, promotions, discounts, taxes, total })
+    }
+
+    fn to_json(&self) -> String {
+        let lines: Vec<String> = self
+            .lines
+            .iter()
+            .map(|line| {
+                format!(
+                    "{{\"sku\":{},\"name\":{},\"category\":{},\"quantity\":{},\"backordered\":{},\"unit_price\":\"{}\",\"total\":\"{}\"}}",
+                    json_string(&line.sku),
+                    json_string(&line.name),
+                    json_string(&line.category),
+                    line.quantity,
+                    line.backordered,
+                    line.unit_price.to_decimal(),
+                    line.total.to_decimal()
+                )
+            })
+            .collect();
+        let promotions: Vec<String> = self
+            .promotions
+            .iter()
+            .map(|promotion| format!("{{\"name\":{},\"amount\":\"{}\"}}", json_string(&promotion.name), promotion.amount.to_decimal()))
+            .collect();
+        let taxes: Vec<String> = self
+            .taxes
+            .iter()
+            .map(|tax| {
+                format!(
+                    "{{\"category\":{},\"basis_points\":{},\"taxable\":\"{}\",\"tax\":\"{}\"}}",
+                    json_string(&tax.category),
+                    tax.basis_points,
+                    tax.taxable.to_decimal(),
+                    tax.tax.to_decimal()
+                )
+            })
+            .collect();
+
+        format!(
+            "{{\"customer\":{},\"lines\":[{}],\"subtotal\":\"{}\",\"promotions\":[{}],\"discounts\":\"{}\",\"taxes\":[{}],\"total\":\"{}\"}}",
+            self.customer,
+            lines.join(","),
+            self.subtotal.to_decimal(),
+            promotions.join(","),
+            self.discounts.to_decimal(),
+            taxes.join(","),
+            self.total.to_decimal()
+        )
+    }
+
+    // One row per entry, tagged by kind, so the accounting import can pick out what it needs
+    fn to_csv(&self) -> String {
+        let mut rows = vec![String::from("kind,sku,description,category,quantity,unit_price,amount")];
+
+        for line in &self.lines {
+            rows.push(format!(
+                "item,{},{},{},{},{},{}",
+                csv_field(&line.sku),
+                csv_field(&line.name),
+                csv_field(&line.category),
+                line.quantity,
+                line.unit_price.to_decimal(),
+                line.total.to_decimal()
+            ));
+        }
+        rows.push(format!("subtotal,,,,,,{}", self.subtotal.to_decimal()));
+        for promotion in &self.promotions {
+            rows.push(format!("promotion,,{},,,,-{}", csv_field(&promotion.name), promotion.amount.to_decimal()));
+        }
+        for tax in &self.taxes {
+            rows.push(format!(
+                "tax,,{},{},,,{}",
+                csv_field(&format!("{}.{:02}% on {}", tax.basis_points / 100, tax.basis_points % 100, tax.taxable.to_decimal())),
+                csv_field(&tax.category),
+                tax.tax.to_decimal()
+            ));
+        }
+        rows.push(format!("total,,,,,,{}", self.total.to_decimal()));
+
+        rows.join("\n")
+    }
+}
+
+impl fmt::Display for Receipt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Customer #{}", self.customer)?;
+        for line in &self.lines {
+            let item = format!("{} x{} @ {}", line.name, line.quantity, line.unit_price);
+            writeln!(f, "{:<36}{:>12}", item, line.total.to_string())?;
+            if line.backordered > 0 {
+                writeln!(f, "  ({} on backorder)", line.backordered)?;
+            }
+        }
+        writeln!(f, "{:<36}{:>12}", "Subtotal", self.subtotal.to_string())?;
+        for promotion in &self.promotions {
+            writeln!(f, "  {:<34}{:>12}", promotion.name, format!("-{}", promotion.amount))?;
+        }
+        for tax in &self.taxes {
+            let label = format!("Tax {} {}.{:02}%", tax.category, tax.basis_points / 100, tax.basis_points % 100);
+            writeln!(f, "  {:<34}{:>12}", label, tax.tax.to_string())?;
+        }
+        write!(f, "{:<36}{:>12}", "Total", self.total.to_string())
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// Quoted only when it has to be, with inner quotes doubled
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+// What to do when a customer wants more than is on the shelf
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WhenOut {
+    Reject,
+    Backorder,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct StockLevel {
+    on_hand: i64,
+    reserved: i64,    // held by checkouts that haven't finished yet
+    reorder_at: i64,  // warn once what's available drops to this
+    when_out: WhenOut,
+    backordered: i64, // sold but still owed, the next restock fills these first
+    opening: i64,     // on hand when the day started
+    sold: i64,
+    received: i64,
+    warned: bool,     // so we warn once, not on every sale after
+}
+
+impl StockLevel {
+    fn new(on_hand: i64, reorder_at: i64, when_out: WhenOut) -> Self {
+        Self { on_hand, reserved: 0, reorder_at, when_out, backordered: 0, opening: on_hand, sold: 0, received: 0, warned: false }
+    }
+
+    fn available(&self) -> i64 {
+        self.on_hand - self.reserved
+    }
+}
+
+/*
+    - Only products with a stock level are counted, anything else sells without limit (the Leetcode catalog never runs out)
+    - reserve -> commit when the customer pays, or release if they walk away
+    - A reservation is all or nothing, one line out of stock and nothing is held
+*/
+struct Inventory {
+    stock: HashMap<String, StockLevel>,
+}
+
+impl Inventory {
+
+    fn new() -> Self {
+        Self {
+            stock: HashMap::new(),
+        }
+    }
+
+    // A recount replaces the count, but what's held for open checkouts and owed to customers carries over
+    fn track(&mut self, sku: &str, mut level: StockLevel) {
+        if let Some(old) = self.stock.get(sku) {
+            level.reserved = old.reserved;
+            level.backordered = old.backordered;
+        }
+        self.stock.insert(sku.to_string(), level);
+    }
+
+    // Fills in each line's backordered amount... the same SKU on two lines counts against the same shelf
+    fn reserve(&mut self, lines: &mut [Line]) -> Result<(), CashierError> {
+        let mut taken: HashMap<&str, i64> = HashMap::new();
+
+        for line in lines.iter_mut() {
+            let Some(level) = self.stock.get(&line.sku) else { continue };
+            let already = taken.entry(&line.sku).or_insert(0);
+            let left = level.available() - *already;
+
+            line.backordered = 0;
+            if line.quantity > left {
+                match level.when_out {
+                    WhenOut::Reject => return Err(CashierError::OutOfStock(line.sku.clone(), line.quantity, left)),
+                    WhenOut::Backorder => line.backordered = line.quantity - left,
+                }
+            }
+            *already += line.quantity - line.backordered;
+        }
+
+        for line in lines.iter() {
+            if let Some(level) = self.stock.get_mut(&line.sku) {
+                level.reserved += line.quantity - line.backordered;
+            }
+        }
+        Ok(())
+    }
+
+    fn release(&mut self, lines: &[Line]) {
+        for line in lines {
+            if let Some(level) = self.stock.get_mut(&line.sku) {
+                level.reserved -= line.quantity - line.backordered;
+            }
+        }
+    }
+
+    // Hands back the low-stock warnings for whoever's listening
+    fn commit(&mut self, lines: &[Line]) -> Vec<String> {
+        let mut warnings = Vec::new();
+
+        for line in lines {
+            let Some(level) = self.stock.get_mut(&line.sku) else { continue };
+            let held = line.quantity - line.backordered;
+
+            level.reserved -= held;
+            level.on_hand -= held;
+            level.sold += line.quantity;
+            level.backordered += line.backordered;
+
+            if line.backordered > 0 {
+                warnings.push(format!("{} is out, {} on backorder", line.sku, level.backordered));
+            }
+            if level.available() <= level.reorder_at && !level.warned {
+                level.warned = true;
+                warnings.push(format!("{} is running low: {} left, reorder at {}", line.sku, level.available(), level.reorder_at));
+            }
+        }
+
+        warnings
+    }
+
+    // Customers waiting on a backorder get served before the shelf... false for a product nobody's counting, it stays that way
+    fn restock(&mut self, sku: &str, quantity: i64) -> bool {
+        let Some(level) = self.stock.get_mut(sku) else { return false };
+        let owed = quantity.min(level.backordered);
+
+        level.backordered -= owed;
+        level.on_hand += quantity - owed;
+        level.received += quantity;
+        if level.available() > level.reorder_at {
+            level.warned = false;
+        }
+        true
+    }
+
+    // Starts the next day as it builds the report
+    fn close_day(&mut self, day: u64) -> StockReport {
+        let mut rows: Vec<StockRow> = self
+            .stock
+            .iter_mut()
+            .map(|(sku, level)| {
+                let row = StockRow {
+                    sku: sku.clone(),
+                    opening: level.opening,
+                    received: level.received,
+                    sold: level.sold,
+                    closing: level.on_hand,
+                    backordered: level.backordered,
+                    low: level.available() <= level.reorder_at,
+                };
+                level.opening = level.on_hand;
+                level.sold = 0;
+                level.received = 0;
+                row
+            })
+            .collect();
+        rows.sort_by(|a, b| a.sku.cmp(&b.sku));
+
+        StockReport { day, rows }
+    }
+}
+
+// A restock file is one "SKU,quantity" per line, blank lines and # comments are skipped
+fn parse_restock(contents: &str) -> Result<Vec<(String, i64)>, CashierError> {
+    contents
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| {
+            let bad = || CashierError::BadRestock(number, line.to_string());
+            let (sku, quantity) = line.split_once(',').ok_or_else(bad)?;
+            let quantity: i64 = quantity.trim().parse().map_err(|_| bad())?;
+            if sku.trim().is_empty() || quantity <= 0 {
+                return Err(bad());
+            }
+            Ok((sku.trim().to_string(), quantity))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct StockRow {
+    sku: String,
+    opening: i64,
+    received: i64,
+    sold: i64,
+    closing: i64,
+    backordered: i64,
+    low: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct StockReport {
+    day: u64, // days since the UNIX epoch
+    rows: Vec<StockRow>,
+}
+
+impl fmt::Display for StockReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Stock report for day {}", self.day)?;
+        writeln!(f, "{:<10}{:>8}{:>10}{:>6}{:>9}{:>12}", "SKU", "opening", "received", "sold", "closing", "backordered")?;
+        for row in &self.rows {
+            write!(f, "{:<10}{:>8}{:>10}{:>6}{:>9}{:>12}", row.sku, row.opening, row.received, row.sold, row.closing, row.backordered)?;
+            writeln!(f, "{}", if row.low { "  LOW" } else { "" })?;
+        }
+        Ok(())
+    }
+}
+
+// Where stock warnings go, Send + Sync since every register can trigger one
+trait Messenger: Send + Sync {
+    fn send(&self, msg: &str);
+}
+
+struct StdoutMessenger;
+
+impl Messenger for StdoutMessenger {
+    fn send(&self, msg: &str) {
+        println!("STOCK {}", msg);
+    }
+}
+
+// Warnings addressed to whoever's on the shelves today
+struct ShiftMessenger {
+    name: String,
+}
+
+impl Messenger for ShiftMessenger {
+    fn send(&self, msg: &str) {
+        println!("To {}: {}", self.name, msg);
+    }
+}
+
+// Held stock for one customer, give it to checkout to sell it... cancelled or just dropped, it goes back on the shelf
+#[must_use]
+struct Reservation<'a> {
+    cashier: &'a Cashier,
+    lines: Vec<Line>,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.cashier.inventory.lock().unwrap_or_else(PoisonError::into_inner).release(&self.lines);
+    }
+}
+
+impl fmt::Debug for Reservation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Reservation").field("lines", &self.lines).finish()
+    }
+}
+
+/*
+    - One Cashier is shared by every register (&self all the way down), set it up first then hand out references
+    - Customer numbers come from an atomic so two registers can never get the same one or skip one
+    - Prices sit behind a RwLock, registers read at the same time and a price change waits for them
+*/
+struct Cashier {
+    customers: AtomicI32,
+    catalog: RwLock<Catalog>,
+    promotions: Vec<Box<dyn Promotion>>,
+    clock: Box<dyn Clock>,
+    tax_rates: TaxRates,
+    inventory: Mutex<Inventory>,
+    messenger: Arc<dyn Messenger>,
+}
+
+
+impl Cashier {
+
+    // Starts with the original every-nth-customer rule, more can be stacked with add_promotion
+    fn new(n: i32, discount: i32, catalog: Catalog) -> Result<Self, CashierError> {
+        let mut cashier = Self {
+            customers: AtomicI32::new(0),
+            catalog: RwLock::new(catalog),
+            promotions: Vec::new(),
+            clock: Box::new(SystemClock),
+            tax_rates: TaxRates::new(0),
+            inventory: Mutex::new(Inventory::new()),
+            messenger: Arc::new(StdoutMessenger),
+        };
+        cashier.add_promotion(Box::new(EveryNthCustomer::new(n, discount as i64, 0, false)?));
+
+        Ok(cashier)
+    }
+
+    // Kept sorted by priority, highest first... equal priorities stay in the order they were added
+    fn add_promotion(&mut self, promotion: Box<dyn Promotion>) {
+        self.promotions.push(promotion);
+        self.promotions.sort_by_key(|promotion| -promotion.priority());
+    }
+
+    fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    fn set_tax_rates(&mut self, tax_rates: TaxRates) {
+        self.tax_rates = tax_rates;
+    }
+
+    fn set_messenger(&mut self, messenger: Arc<dyn Messenger>) {
+        self.messenger = messenger;
+    }
+
+    // Starts counting a product, or recounts one... held and backordered stock is kept
+    fn track_stock(&self, sku: &str, on_hand: i32, reorder_at: i32, when_out: WhenOut) -> Result<(), CashierError> {
+        self.catalog.read().unwrap_or_else(PoisonError::into_inner).get(sku)?;
+        if on_hand < 0 {
+            return Err(CashierError::InvalidQuantity(sku.to_string(), on_hand));
+        }
+
+        self.inventory
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .track(sku, StockLevel::new(on_hand as i64, reorder_at as i64, when_out));
+        Ok(())
+    }
+
+    // The whole file is checked before any of it goes on the shelf, hands back how many lines were applied...
+    // products that aren't being counted are skipped, start counting them with track_stock first
+    fn restock_from_file(&self, path: &Path) -> Result<usize, CashierError> {
+        let contents = fs::read_to_string(path).map_err(|e| CashierError::Io(format!("{}: {}", path.display(), e)))?;
+        let deliveries = parse_restock(&contents)?;
+
+        let catalog = self.catalog.read().unwrap_or_else(PoisonError::into_inner);
+        for (sku, _) in &deliveries {
+            catalog.get(sku)?;
+        }
+        drop(catalog);
+
+        let mut inventory = self.inventory.lock().unwrap_or_else(PoisonError::into_inner);
+        let applied = deliveries.iter().filter(|(sku, quantity)| inventory.restock(sku, *quantity)).count();
+
+        Ok(applied)
+    }
+
+    fn close_day(&self) -> StockReport {
+        let day = self.clock.now() / 86_400;
+        self.inventory.lock().unwrap_or_else(PoisonError::into_inner).close_day(day)
+    }
+
+    // Live price changes, bills already being rung up keep the price they read
+    fn set_price(&self, sku: &str, price: Money) -> Result<Money, CashierError> {
+        self.catalog.write().unwrap_or_else(PoisonError::into_inner).set_price(sku, price)
+    }
+
+    fn add_product(&self, sku: &str, name: &str, category: &str, price: Money) -> Result<(), CashierError> {
+        self.catalog.write().unwrap_or_else(PoisonError::into_inner).add(sku, name, category, price)
+    }
+
+    // Numbers start at 1, and a full counter is an error for this register instead of a panic on a shared till
+    fn next_customer(&self) -> Result<i32, CashierError> {
+        self.customers
+            .fetch_update(Relaxed, Relaxed, |customer| customer.checked_add(1))
+            .map(|customer| customer + 1)
+            .map_err(|_| CashierError::TooManyCustomers)
+    }
+
+    fn apply_promotions(&self, customer: i32, lines: &[Line], subtotal: Money) -> (Vec<AppliedPromotion>, Money) {
+        let hour = ((self.clock.now() % 86_400) / 3_600) as u32;
+        let mut applied: Vec<AppliedPromotion> = Vec::new();
+        let mut running_total = subtotal;
+
+        for promotion in &self.promotions {
+            if promotion.exclusive() && !applied.is_empty() {
+                continue;
+            }
+
+            let checkout = Checkout { lines, customer, hour, running_total };
+
+            // Never take off more than is left to pay, and a SKU promotion no more than is left of its line
+            let left = match promotion.sku() {
+                Some(sku) => {
+                    let line = lines.iter().find(|line| line.sku == sku).map_or(Money(0), |line| line.total);
+                    let taken: Money = applied.iter().filter(|earlier| earlier.sku.as_deref() == Some(sku)).map(|earlier| earlier.amount).sum();
+                    running_total.min(line - taken)
+                }
+                None => running_total,
+            };
+            let amount = match promotion.discount(&checkout) {
+                Some(amount) if amount.cents() > 0 && left.cents() > 0 => amount.min(left),
+                _ => continue,
+            };
+
+            running_total = Money(running_total.cents() - amount.cents());
+            applied.push(AppliedPromotion { name: promotion.name(), sku: promotion.sku().map(String::from), amount });
+
+            if promotion.exclusive() {
+                break;
+            }
+        }
+
+        (applied, running_total)
+    }
+
+    // The whole order is checked and its stock held before this customer is counted, so a bad order doesn't use up
+    // someone's discount
+    fn reserve(&self, product: &[&str], amount: &[i32]) -> Result<Reservation<'_>, CashierError> {
+        if product.len() != amount.len() {
+            return Err(CashierError::MismatchedLines(product.len(), amount.len()));
+        }
+
+        let catalog = self.catalog.read().unwrap_or_else(PoisonError::into_inner);
+        let mut lines: Vec<Line> = product
                             .iter()
                             .zip(amount.iter())
-                            .map(|(&item, &taken)| self.items[&item] as f64 * taken as f64)
-                            .sum();
+                            .map(|(&item, &taken)| {
+                                if taken < 0 {
+                                    return Err(CashierError::InvalidQuantity(item.to_string(), taken));
+                                }
+                                let product = catalog.get(item)?;
+                                let total = product.price.times(taken as i64).ok_or_else(|| CashierError::InvalidQuantity(item.to_string(), taken))?;
+                                Ok(Line {
+                                    sku: product.sku.clone(),
+                                    name: product.name.clone(),
+                                    category: product.category.clone(),
+                                    quantity: taken as i64,
+                                    unit_price: product.price,
+                                    total,
+                                    backordered: 0,
+                                })
+                            })
+                            .collect::<Result<Vec<Line>, CashierError>>()?;
 
-        if self.length % self.n == 0 {
-            self.apply_discount(result)
-        } else {
-            result
+        // Each line fitting isn't enough, the whole bill has to as well
+        lines.iter().try_fold(Money(0), |subtotal, line| {
+            subtotal.checked_add(line.total).ok_or_else(|| CashierError::InvalidQuantity(line.sku.clone(), line.quantity as i32))
+        })?;
+
+        drop(catalog);
+
+        self.inventory.lock().unwrap_or_else(PoisonError::into_inner).reserve(&mut lines)?;
+
+        Ok(Reservation { cashier: self, lines })
+    }
+
+    // Same as letting it go out of scope, just says so
+    fn cancel(&self, reservation: Reservation) {
+        drop(reservation);
+    }
+
+    // Numbered and priced before anything is sold, so a till that's out of numbers or a bill too big to add up drops the
+    // reservation and the stock goes back
+    fn checkout(&self, mut reservation: Reservation) -> Result<Receipt, CashierError> {
+        let customer = self.next_customer()?;
+
+        let subtotal: Money = reservation.lines.iter().map(|line| line.total).sum();
+        let (promotions, _) = self.apply_promotions(customer, &reservation.lines, subtotal);
+        let receipt = Receipt::new(customer, reservation.lines.clone(), promotions, &self.tax_rates)?;
+
+        // Emptied, so there's nothing left for it to release when it drops
+        reservation.lines.clear();
+        let warnings = self.inventory.lock().unwrap_or_else(PoisonError::into_inner).commit(&receipt.lines);
+        for warning in warnings {
+            self.messenger.send(&warning);
+        }
+
+        Ok(receipt)
+    }
+
+    fn get_bill(&self, product: &[&str], amount: &[i32]) -> Result<Receipt, CashierError> {
+        let reservation = self.reserve(product, amount)?;
+        self.checkout(reservation)
+    }
+}
+
+fn main() {
+    let mut catalog = Catalog::new();
+    for (sku, name, category, price) in [
+        ("APL-001", "Apple", "produce", "0.10"),
+        ("BAN-002", "Banana", "produce", "0.20"),
+        ("CHZ-003", "Cheddar, aged", "dairy", "4.99"),
+    ] {
+        match price.parse() {
+            Ok(price) => catalog.add(sku, name, category, price).unwrap(),
+            Err(e) => println!("{}", e),
+        }
+    }
+
+    // Every 3rd customer gets 50% off, on top of this week's offers... at 17:30 on day 19675 (14 Nov 2023)
+    let mut cashier = Cashier::new(3, 50, catalog).unwrap();
+    cashier.set_clock(Box::new(FixedClock(19_675 * 86_400 + 17 * 3_600 + 30 * 60)));
+    cashier.add_promotion(Box::new(BuyXGetY::new("APL-001", 2, 1, 30, false).unwrap()));
+    cashier.add_promotion(Box::new(SkuPercentOff::new("CHZ-003", 10, 20, false).unwrap()));
+    cashier.add_promotion(Box::new(ThresholdCoupon { minimum: Money::from_cents(1_000), off: Money::from_cents(200), priority: 10, exclusive: false }));
+    cashier.add_promotion(Box::new(TimeOfDay::new(17, 19, 5, 5, false).unwrap()));
+    if let Err(e) = BuyXGetY::new("BAN-002", 0, 0, 30, false) {
+        println!("{}", e);
+    }
+    if let Err(e) = SkuPercentOff::new("BAN-002", 300, 20, false) {
+        println!("{}", e);
+    }
+
+    // Produce is tax free, dairy pays 6.25%
+    let mut tax_rates = TaxRates::new(825);
+    tax_rates.set("produce", 0);
+    tax_rates.set("dairy", 625);
+    cashier.set_tax_rates(tax_rates);
+    cashier.set_messenger(Arc::new(ShiftMessenger { name: String::from("the stockroom") }));
+
+    // Apples are turned away when they run out, cheese can be ordered in, bananas aren't counted
+    cashier.track_stock("APL-001", 10, 4, WhenOut::Reject).unwrap();
+    cashier.track_stock("CHZ-003", 4, 1, WhenOut::Backorder).unwrap();
+
+    let orders: [(&[&str], &[i32]); 5] = [
+        (&["APL-001", "BAN-002"], &[3, 2]),
+        (&["CHZ-003"], &[3]),
+        (&["APL-001", "CHZ-003"], &[3, 1]),
+        (&["MLK-404"], &[1]),
+        (&["APL-001"], &[5]),
+    ];
+
+    for (product, amount) in orders {
+        match cashier.get_bill(product, amount) {
+            Ok(receipt) => println!("{}\n", receipt),
+            Err(e) => println!("Couldn't ring that up: {}", e),
+        }
+    }
+
+    if let Ok(receipt) = cashier.get_bill(&["BAN-002", "CHZ-003"], &[4, 2]) {
+        println!("{}\n", receipt.to_json());
+        println!("{}\n", receipt.to_csv());
+    }
+
+    // Someone changes their mind at the till, the apples go back on the shelf
+    match cashier.reserve(&["APL-001"], &[4]) {
+        Ok(reservation) => cashier.cancel(reservation),
+        Err(e) => println!("Couldn't ring that up: {}", e),
+    }
+
+    // The evening delivery, the cheese goes to the backorders first
+    let delivery = std::env::temp_dir().join("ch8_restock.csv");
+    let restocked = fs::write(&delivery, "# sku,quantity\nAPL-001,12\nCHZ-003,6\n")
+        .map_err(|e| CashierError::Io(e.to_string()))
+        .and_then(|_| cashier.restock_from_file(&delivery));
+    match restocked {
+        Ok(lines) => println!("Restocked {} products", lines),
+        Err(e) => println!("{}", e),
+    }
+    println!("{}", cashier.close_day());
+
+    // Four registers sharing one till, the price of apples goes up half way through the day
+    let shop = Cashier::new(5, 20, Catalog::from_ids(vec![1, 2], vec![1, 3]).unwrap()).unwrap();
+    let discounted = thread::scope(|s| {
+        let registers: Vec<_> = (0..4)
+            .map(|_| {
+                s.spawn(|| {
+                    (0..50)
+                        .filter_map(|_| shop.get_bill(&["1", "2"], &[2, 1]).ok())
+                        .filter(|receipt| !receipt.promotions.is_empty())
+                        .count()
+                })
+            })
+            .collect();
+
+        if let Ok(old) = shop.set_price("1", Money::from_cents(150)) {
+            println!("Apples went from {} to {}", old, Money::from_cents(150));
+        }
+        if let Err(e) = shop.add_product("3", "Product 3", "general", Money::from_cents(500)) {
+            println!("{}", e);
+        }
+
+        registers.into_iter().map(|register| register.join().unwrap()).sum::<usize>()
+    });
+    println!("{} of 200 customers got the every 5th discount", discounted);
+
+    // The Leetcode example still works
+    let leetcode = Cashier::new(3, 50, Catalog::from_ids(vec![1, 2, 3, 4, 5, 6, 7], vec![100, 200, 300, 400, 300, 200, 100]).unwrap()).unwrap();
+    for _ in 0..3 {
+        println!("Leetcode bill: {:?}", leetcode.get_bill(&["1", "2"], &[1, 2]).map(|receipt| receipt.total.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cashier() -> Cashier {
+        let mut catalog = Catalog::new();
+        catalog.add("APL-001", "Apple", "produce", Money::from_cents(100)).unwrap();
+        catalog.add("CHZ-003", "Cheddar", "dairy", Money::from_cents(500)).unwrap();
+
+        let mut cashier = Cashier::new(2, 50, catalog).unwrap();
+        cashier.set_clock(Box::new(FixedClock(12 * 3_600)));
+        cashier
+    }
+
+    struct MockMessenger {
+        sent_messages: Mutex<Vec<String>>,
+    }
+
+    impl Messenger for MockMessenger {
+        fn send(&self, message: &str) {
+            self.sent_messages.lock().unwrap().push(String::from(message));
+        }
+    }
+
+    #[test]
+    fn prices_parse_to_the_cent() {
+        assert_eq!("12".parse::<Money>(), Ok(Money::from_cents(1_200)));
+        assert_eq!("12.5".parse::<Money>(), Ok(Money::from_cents(1_250)));
+        assert_eq!(" $0.07 ".parse::<Money>(), Ok(Money::from_cents(7)));
+        assert_eq!("92233720368547758.07".parse::<Money>(), Ok(Money::from_cents(i64::MAX)));
+
+        for bad in ["", "abc", ".50", "1.234", "-1.00", "1.2.3", "92233720368547758.08", "92233720368547759"] {
+            assert_eq!(bad.parse::<Money>(), Err(CashierError::InvalidPrice(String::from(bad))), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn bills_too_big_to_add_up_are_refused_not_wrapped() {
+        let mut catalog = Catalog::new();
+        catalog.add("GLD-001", "Gold bar", "metals", Money::from_cents(i64::MAX)).unwrap();
+        catalog.add("SLV-002", "Silver bar", "metals", Money::from_cents(i64::MAX / 2 + 1)).unwrap();
+        let mut cashier = Cashier::new(2, 50, catalog).unwrap();
+        cashier.track_stock("GLD-001", 5, 0, WhenOut::Reject).unwrap();
+
+        // Too much on one line, then two lines that only overflow together... neither gets a customer number
+        assert_eq!(cashier.get_bill(&["GLD-001"], &[2]).unwrap_err(), CashierError::InvalidQuantity(String::from("GLD-001"), 2));
+        assert_eq!(cashier.get_bill(&["SLV-002", "SLV-002"], &[1, 1]).unwrap_err(), CashierError::InvalidQuantity(String::from("SLV-002"), 1));
+
+        // The biggest bill there is still rings up, and 50% off it doesn't overflow on the way (the odd cent rounds up)
+        assert_eq!(cashier.get_bill(&["GLD-001"], &[1]).unwrap().total, Money::from_cents(i64::MAX));
+        let receipt = cashier.get_bill(&["GLD-001"], &[1]).unwrap();
+        assert_eq!(receipt.customer, 2);
+        assert_eq!(receipt.total, Money::from_cents(i64::MAX / 2 + 1));
+
+        // Tax that pushes it over is an error too, and the gold stays on the shelf
+        cashier.set_tax_rates(TaxRates::new(825));
+        assert!(matches!(cashier.get_bill(&["GLD-001"], &[1]), Err(CashierError::InvalidPrice(_))));
+        assert_eq!(cashier.close_day().rows[0].closing, 3);
+    }
+
+    #[test]
+    fn unknown_skus_are_turned_away_without_a_customer_number() {
+        let cashier = cashier();
+
+        assert_eq!(cashier.get_bill(&["APL-001", "MLK-404"], &[1, 1]).unwrap_err(), CashierError::UnknownProduct(String::from("MLK-404")));
+        assert_eq!(cashier.get_bill(&["APL-001"], &[1]).unwrap().customer, 1);
+    }
+
+    #[test]
+    fn stock_is_held_until_checkout() {
+        let messenger = Arc::new(MockMessenger { sent_messages: Mutex::new(vec![]) });
+        let mut cashier = cashier();
+        cashier.set_messenger(messenger.clone());
+        cashier.track_stock("APL-001", 5, 2, WhenOut::Reject).unwrap();
+
+        // Held stock can't be sold twice, and the same SKU on two lines counts against one shelf
+        let held = cashier.reserve(&["APL-001"], &[4]).unwrap();
+        assert_eq!(cashier.reserve(&["APL-001"], &[2]).unwrap_err(), CashierError::OutOfStock(String::from("APL-001"), 2, 1));
+        cashier.cancel(held);
+        assert_eq!(
+            cashier.reserve(&["APL-001", "APL-001"], &[3, 3]).unwrap_err(),
+            CashierError::OutOfStock(String::from("APL-001"), 3, 2)
+        );
+
+        // Nothing above got a customer number, so this is still the 1st... warned once on the way down
+        let receipt = cashier.get_bill(&["APL-001", "CHZ-003"], &[3, 1]).unwrap();
+        assert_eq!(receipt.customer, 1);
+        cashier.get_bill(&["APL-001"], &[1]).unwrap();
+        assert_eq!(*messenger.sent_messages.lock().unwrap(), vec!["APL-001 is running low: 2 left, reorder at 2"]);
+        assert!(cashier.track_stock("MLK-404", 1, 0, WhenOut::Reject).is_err());
+    }
+
+    #[test]
+    fn recounting_keeps_held_and_owed_stock() {
+        let cashier = cashier();
+        cashier.track_stock("APL-001", 5, 0, WhenOut::Reject).unwrap();
+        cashier.track_stock("CHZ-003", 1, 0, WhenOut::Backorder).unwrap();
+        cashier.get_bill(&["CHZ-003"], &[3]).unwrap();
+        let held = cashier.reserve(&["APL-001"], &[4]).unwrap();
+
+        // A recount while 4 apples are held and 2 cheeses are owed
+        cashier.track_stock("APL-001", 5, 0, WhenOut::Reject).unwrap();
+        cashier.track_stock("CHZ-003", 0, 0, WhenOut::Backorder).unwrap();
+        assert_eq!(cashier.reserve(&["APL-001"], &[2]).unwrap_err(), CashierError::OutOfStock(String::from("APL-001"), 2, 1));
+
+        // Letting go gives back exactly what was held, so the shelf can't be oversold afterwards
+        drop(held);
+        cashier.get_bill(&["APL-001"], &[5]).unwrap();
+        assert_eq!(cashier.reserve(&["APL-001"], &[1]).unwrap_err(), CashierError::OutOfStock(String::from("APL-001"), 1, 0));
+        assert_eq!(cashier.close_day().rows[1].backordered, 2);
+    }
+
+    #[test]
+    fn dropped_reservations_give_their_stock_back() {
+        let cashier = cashier();
+        cashier.track_stock("APL-001", 5, 0, WhenOut::Reject).unwrap();
+
+        {
+            let _walked_away = cashier.reserve(&["APL-001"], &[4]).unwrap();
+            assert!(cashier.reserve(&["APL-001"], &[2]).is_err());
+        }
+
+        // Checked out, the reservation has nothing left to hand back when it goes
+        let all = cashier.reserve(&["APL-001"], &[5]).unwrap();
+        let receipt = cashier.checkout(all).unwrap();
+        assert_eq!(receipt.lines[0].quantity, 5);
+        let inventory = cashier.inventory.lock().unwrap();
+        assert_eq!((inventory.stock["APL-001"].on_hand, inventory.stock["APL-001"].reserved), (0, 0));
+    }
+
+    #[test]
+    fn restocks_fill_backorders_first() {
+        let messenger = Arc::new(MockMessenger { sent_messages: Mutex::new(vec![]) });
+        let mut cashier = cashier();
+        cashier.set_messenger(messenger.clone());
+        cashier.track_stock("CHZ-003", 2, 0, WhenOut::Backorder).unwrap();
+
+        let receipt = cashier.get_bill(&["CHZ-003"], &[5]).unwrap();
+        assert_eq!(receipt.lines[0].backordered, 3);
+        assert_eq!(receipt.subtotal, Money::from_cents(2_500));
+        assert_eq!(messenger.sent_messages.lock().unwrap()[0], "CHZ-003 is out, 3 on backorder");
+
+        // A bad line anywhere and none of the file is applied
+        let file = std::env::temp_dir().join(format!("cashier-restock-{}.csv", std::process::id()));
+        fs::write(&file, "CHZ-003,4\nAPL-001,lots\n").unwrap();
+        assert_eq!(cashier.restock_from_file(&file).unwrap_err(), CashierError::BadRestock(2, String::from("APL-001,lots")));
+        // Apples aren't being counted, so their delivery doesn't start a count for them
+        fs::write(&file, "# morning delivery\nCHZ-003, 4\n\nAPL-001,10\n").unwrap();
+        assert_eq!(cashier.restock_from_file(&file).unwrap(), 1);
+        fs::write(&file, "MLK-404,1\n").unwrap();
+        assert_eq!(cashier.restock_from_file(&file).unwrap_err(), CashierError::UnknownProduct(String::from("MLK-404")));
+        fs::remove_file(&file).unwrap();
+
+        let report = cashier.close_day();
+        assert_eq!(report.day, 0);
+        assert_eq!(
+            report.rows,
+            vec![StockRow { sku: String::from("CHZ-003"), opening: 2, received: 4, sold: 5, closing: 1, backordered: 0, low: false }]
+        );
+        assert!(cashier.get_bill(&["APL-001"], &[50]).is_ok());
+
+        // The next day starts from last night's closing
+        let report = cashier.close_day();
+        assert_eq!((report.rows[0].opening, report.rows[0].sold), (1, 0));
+    }
+
+    #[test]
+    fn every_nth_bill_is_discounted_across_registers() {
+        let cashier = Cashier::new(3, 50, Catalog::from_ids(vec![1, 2], vec![2, 4]).unwrap()).unwrap();
+
+        let receipts: Vec<Receipt> = thread::scope(|s| {
+            let registers: Vec<_> = (0..8)
+                .map(|_| s.spawn(|| (0..300).map(|_| cashier.get_bill(&["1", "2"], &[1, 1]).unwrap()).collect::<Vec<_>>()))
+                .collect();
+
+            // Bad orders on the side shouldn't use up a customer number
+            for _ in 0..100 {
+                assert!(cashier.get_bill(&["9"], &[1]).is_err());
+            }
+
+            registers.into_iter().flat_map(|register| register.join().unwrap()).collect()
+        });
+
+        // Every customer number handed out once, no gaps
+        let mut customers: Vec<i32> = receipts.iter().map(|receipt| receipt.customer).collect();
+        customers.sort();
+        assert_eq!(customers, (1..=2_400).collect::<Vec<_>>());
+
+        for receipt in &receipts {
+            let discounted = !receipt.promotions.is_empty();
+            assert_eq!(discounted, receipt.customer % 3 == 0, "customer {}", receipt.customer);
+            assert_eq!(receipt.total, Money::from_cents(if discounted { 300 } else { 600 }));
         }
+        assert_eq!(receipts.iter().filter(|receipt| !receipt.promotions.is_empty()).count(), 800);
+    }
+
+    #[test]
+    fn a_till_out_of_numbers_refuses_the_sale_and_keeps_the_stock() {
+        let cashier = cashier();
+        cashier.track_stock("APL-001", 5, 0, WhenOut::Reject).unwrap();
+        cashier.customers.store(i32::MAX - 1, Relaxed);
+
+        assert_eq!(cashier.get_bill(&["APL-001"], &[1]).unwrap().customer, i32::MAX);
+        assert_eq!(cashier.get_bill(&["APL-001"], &[1]).unwrap_err(), CashierError::TooManyCustomers);
+        assert_eq!(cashier.get_bill(&["APL-001"], &[1]).unwrap_err(), CashierError::TooManyCustomers);
+
+        let inventory = cashier.inventory.lock().unwrap();
+        let apples = &inventory.stock["APL-001"];
+        assert_eq!((apples.on_hand, apples.reserved, apples.sold), (4, 0, 1));
+    }
+
+    #[test]
+    fn prices_change_while_registers_are_open() {
+        let cashier = cashier();
+
+        thread::scope(|s| {
+            let register = s.spawn(|| {
+                (0..1_000)
+                    .map(|_| cashier.get_bill(&["APL-001"], &[1]).unwrap().subtotal)
+                    .collect::<Vec<_>>()
+            });
+            assert_eq!(cashier.set_price("APL-001", Money::from_cents(120)).unwrap(), Money::from_cents(100));
+
+            // A bill sees the old price or the new one, never anything else
+            for subtotal in register.join().unwrap() {
+                assert!(subtotal == Money::from_cents(100) || subtotal == Money::from_cents(120));
+            }
+        });
+
+        assert_eq!(cashier.get_bill(&["APL-001"], &[1]).unwrap().subtotal, Money::from_cents(120));
+        assert!(matches!(cashier.set_price("APL-001", Money::from_cents(-1)), Err(CashierError::InvalidPrice(_))));
+        assert!(matches!(cashier.set_price("MLK-404", Money::from_cents(1)), Err(CashierError::UnknownProduct(_))));
+    }
+
+    #[test]
+    fn promotions_stack_by_priority() {
+        let mut cashier = cashier();
+        cashier.add_promotion(Box::new(ThresholdCoupon { minimum: Money::from_cents(500), off: Money::from_cents(100), priority: 10, exclusive: false }));
+        cashier.add_promotion(Box::new(BuyXGetY::new("APL-001", 1, 1, 30, false).unwrap()));
+
+        // 4 apples, 2 free -> 2.00, not enough left for the coupon
+        let receipt = cashier.get_bill(&["APL-001"], &[4]).unwrap();
+        assert_eq!(receipt.promotions.len(), 1);
+        assert_eq!(receipt.total, Money::from_cents(200));
+
+        // Second customer: 2 apples (1 free) + cheese = 6.00, then 1.00 coupon, then half off
+        let receipt = cashier.get_bill(&["APL-001", "CHZ-003"], &[2, 1]).unwrap();
+        let names: Vec<&str> = receipt.promotions.iter().map(|promotion| promotion.name.as_str()).collect();
+        assert_eq!(names, vec!["Buy 1 get 1 free on APL-001", "$1.00 off when you spend $5.00", "Every 2nd customer 50% off"]);
+        assert_eq!(receipt.total, Money::from_cents(250));
+    }
+
+    #[test]
+    fn exclusive_promotions_stand_alone() {
+        let mut cashier = cashier();
+        cashier.add_promotion(Box::new(ThresholdCoupon { minimum: Money::from_cents(500), off: Money::from_cents(300), priority: 10, exclusive: true }));
+
+        // Nothing ran before the coupon, so it applies and shuts out the 2nd customer discount
+        cashier.get_bill(&["APL-001"], &[1]).unwrap();
+        let receipt = cashier.get_bill(&["CHZ-003"], &[1]).unwrap();
+        assert_eq!(receipt.promotions.len(), 1);
+        assert_eq!(receipt.total, Money::from_cents(200));
+
+        // With a higher priority rule already applied, the exclusive coupon is skipped
+        cashier.add_promotion(Box::new(SkuPercentOff::new("CHZ-003", 10, 20, false).unwrap()));
+        let receipt = cashier.get_bill(&["CHZ-003"], &[2]).unwrap();
+        assert_eq!(receipt.promotions.len(), 1);
+        assert_eq!(receipt.total, Money::from_cents(900));
+    }
+
+    #[test]
+    fn priority_and_exclusivity_belong_to_each_promotion() {
+        let mut coupons = cashier();
+
+        // Two coupons of the same kind: the exclusive one is ranked first and keeps everything else off the bill
+        coupons.add_promotion(Box::new(ThresholdCoupon { minimum: Money::from_cents(0), off: Money::from_cents(50), priority: 10, exclusive: false }));
+        coupons.add_promotion(Box::new(ThresholdCoupon { minimum: Money::from_cents(0), off: Money::from_cents(100), priority: 40, exclusive: true }));
+        let receipt = coupons.get_bill(&["CHZ-003"], &[1]).unwrap();
+        assert_eq!(receipt.promotions.len(), 1);
+        assert_eq!(receipt.discounts, Money::from_cents(100));
+
+        // A buy-x-get-y below the every 2nd customer rule sees the halved total... and still takes a free apple off
+        let mut after_nth = cashier();
+        after_nth.add_promotion(Box::new(BuyXGetY::new("APL-001", 1, 1, -1, false).unwrap()));
+        after_nth.get_bill(&["CHZ-003"], &[1]).unwrap();
+        let receipt = after_nth.get_bill(&["APL-001"], &[2]).unwrap();
+        let names: Vec<&str> = receipt.promotions.iter().map(|promotion| promotion.name.as_str()).collect();
+        assert_eq!(names, vec!["Every 2nd customer 50% off", "Buy 1 get 1 free on APL-001"]);
+        assert_eq!(receipt.total, Money::from_cents(0));
+    }
+
+    #[test]
+    fn buy_nothing_get_nothing_is_refused() {
+        for (buy, free) in [(0, 0), (0, 1), (1, 0), (-1, 2), (i64::MAX, 1)] {
+            assert!(matches!(BuyXGetY::new("APL-001", buy, free, 30, false), Err(CashierError::InvalidPromotion(_))), "{} {}", buy, free);
+        }
+        assert!(BuyXGetY::new("APL-001", 2, 1, 30, false).is_ok());
+    }
+
+    #[test]
+    fn percentages_outside_0_to_100_are_refused() {
+        for percent in [-1, 101, 300] {
+            assert!(matches!(EveryNthCustomer::new(2, percent, 0, false), Err(CashierError::InvalidPromotion(_))), "{}", percent);
+            assert!(matches!(SkuPercentOff::new("APL-001", percent, 20, false), Err(CashierError::InvalidPromotion(_))), "{}", percent);
+            assert!(matches!(TimeOfDay::new(17, 19, percent, 5, false), Err(CashierError::InvalidPromotion(_))), "{}", percent);
+        }
+        assert!(matches!(TimeOfDay::new(17, 24, 5, 5, false), Err(CashierError::InvalidPromotion(_))));
+        assert!(matches!(Cashier::new(2, 300, Catalog::new()), Err(CashierError::InvalidPromotion(_))));
+        assert!(SkuPercentOff::new("APL-001", 0, 20, false).is_ok());
+        assert!(SkuPercentOff::new("APL-001", 100, 20, false).is_ok());
+    }
+
+    #[test]
+    fn sku_promotions_never_take_more_than_their_line() {
+        let mut cashier = cashier();
+        cashier.add_promotion(Box::new(BuyXGetY::new("APL-001", 1, 1, 30, false).unwrap()));
+        cashier.add_promotion(Box::new(SkuPercentOff::new("APL-001", 100, 20, false).unwrap()));
+
+        // Half the apples are already free, so the 100% off only gets the other half... the cheese is still paid for
+        let receipt = cashier.get_bill(&["APL-001", "CHZ-003"], &[2, 1]).unwrap();
+        let amounts: Vec<Money> = receipt.promotions.iter().map(|promotion| promotion.amount).collect();
+        assert_eq!(amounts, vec![Money::from_cents(100), Money::from_cents(100)]);
+        assert_eq!(receipt.total, Money::from_cents(500));
+    }
+
+    #[test]
+    fn discounts_are_shared_out_before_tax() {
+        let mut cashier = cashier();
+        let mut tax_rates = TaxRates::new(0);
+        tax_rates.set("dairy", 1_000);
+        cashier.set_tax_rates(tax_rates);
+        cashier.add_promotion(Box::new(ThresholdCoupon { minimum: Money::from_cents(0), off: Money::from_cents(1), priority: 10, exclusive: false }));
+
+        // 3.00 of apples and 5.00 of cheese, one cent off: the cent lands on the bigger remainder (cheese)
+        let receipt = cashier.get_bill(&["APL-001", "CHZ-003"], &[3, 1]).unwrap();
+        assert_eq!(receipt.discounts, Money::from_cents(1));
+        assert_eq!(receipt.taxes[0].taxable, Money::from_cents(499));
+        assert_eq!(receipt.taxes[0].tax, Money::from_cents(50));
+        assert_eq!(receipt.taxes[1].taxable, Money::from_cents(300));
+        assert_eq!(receipt.total, Money::from_cents(800 - 1 + 50));
+
+        let csv = receipt.to_csv();
+        assert!(csv.ends_with("total,,,,,,8.49"));
+        assert!(receipt.to_json().ends_with("\"total\":\"8.49\"}"));
     }
 }